@@ -0,0 +1,275 @@
+// Cover art lookup for loaded tracks
+//
+// Sources in order: the artwork file from the export database, the cover embedded in the audio
+// file (ID3v2 APIC, MP4 covr or FLAC PICTURE) and a cover image next to the audio file. Only the
+// tags are read from audio files, not the audio itself.
+
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::beatkeeper::TrackInfo;
+
+const SIDECAR_NAMES: [&str; 4] = ["cover.jpg", "folder.jpg", "cover.png", "folder.png"];
+
+pub struct Artwork {
+    pub data: Vec<u8>,
+    pub mime: &'static str,
+}
+
+impl Artwork {
+    fn new(data: Vec<u8>) -> Option<Self> {
+        let mime = mime_from_data(&data)?;
+        Some(Self { data, mime })
+    }
+
+    /// File extension for the image type
+    pub fn extension(&self) -> &'static str {
+        match self.mime {
+            "image/png" => "png",
+            _ => "jpg",
+        }
+    }
+}
+
+/// Find the cover art of `track`, if there is any
+pub fn find_artwork(track: &TrackInfo) -> Option<Artwork> {
+    if !track.artwork.is_empty() {
+        // Exports store a thumbnail and a larger version with an _m suffix
+        let path = Path::new(&track.artwork);
+        if let (Some(stem), Some(ext)) = (path.file_stem(), path.extension()) {
+            let large = path.with_file_name(format!("{}_m.{}", stem.to_string_lossy(), ext.to_string_lossy()));
+            if let Some(art) = fs::read(large).ok().and_then(Artwork::new) {
+                return Some(art);
+            }
+        }
+        if let Some(art) = fs::read(path).ok().and_then(Artwork::new) {
+            return Some(art);
+        }
+    }
+
+    if track.file_path.is_empty() {
+        return None;
+    }
+    let path = Path::new(&track.file_path);
+    if let Some(art) = embedded_artwork(path).and_then(Artwork::new) {
+        return Some(art);
+    }
+
+    let dir = path.parent()?;
+    SIDECAR_NAMES
+        .iter()
+        .find_map(|name| fs::read(dir.join(name)).ok().and_then(Artwork::new))
+}
+
+fn mime_from_data(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"\x89PNG") {
+        Some("image/png")
+    } else {
+        None
+    }
+}
+
+fn embedded_artwork(path: &Path) -> Option<Vec<u8>> {
+    let mut file = File::open(path).ok()?;
+    let mut header = [0u8; 10];
+    file.read_exact(&mut header).ok()?;
+    if header.starts_with(b"ID3") {
+        // The tag is at the start, its size excludes the 10 byte header
+        let size = 10 + syncsafe_u32(&header, 6)? as usize;
+        id3_artwork(&read_at(&mut file, 0, size)?)
+    } else if header.starts_with(b"fLaC") {
+        flac_artwork(&mut file)
+    } else if &header[4..8] == b"ftyp" {
+        mp4_artwork(&mp4_moov(&mut file)?)
+    } else {
+        None
+    }
+}
+
+fn read_at(file: &mut File, at: u64, len: usize) -> Option<Vec<u8>> {
+    file.seek(SeekFrom::Start(at)).ok()?;
+    let mut data = vec![0; len];
+    file.read_exact(&mut data).ok()?;
+    Some(data)
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn syncsafe_u32(data: &[u8], at: usize) -> Option<u32> {
+    let b = data.get(at..at + 4)?;
+    Some(((b[0] as u32) << 21) | ((b[1] as u32) << 14) | ((b[2] as u32) << 7) | b[3] as u32)
+}
+
+/// Skip a zero terminated string in the given ID3 text encoding, returning the index after it
+fn skip_id3_string(data: &[u8], start: usize, encoding: u8) -> Option<usize> {
+    if encoding == 1 || encoding == 2 {
+        // UTF-16, terminated by two zero bytes on an even offset
+        (start..data.len().saturating_sub(1))
+            .step_by(2)
+            .find(|&i| data[i] == 0 && data[i + 1] == 0)
+            .map(|i| i + 2)
+    } else {
+        data.get(start..)?.iter().position(|&b| b == 0).map(|i| start + i + 1)
+    }
+}
+
+/// Undo ID3 unsynchronisation, which inserts a zero after every 0xFF
+fn resync(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (i, &b) in data.iter().enumerate() {
+        if b == 0 && i > 0 && data[i - 1] == 0xFF {
+            continue;
+        }
+        out.push(b);
+    }
+    out
+}
+
+fn id3_artwork(tag: &[u8]) -> Option<Vec<u8>> {
+    let version = *tag.get(3)?;
+    let flags = *tag.get(5)?;
+    let unsynchronised = flags & 0x80 != 0;
+    // Before ID3v2.4 unsynchronisation applies to the whole tag, 2.4 marks it per frame
+    let data: Cow<[u8]> = if unsynchronised && version < 4 {
+        Cow::Owned([&tag[..10], &resync(&tag[10..])].concat())
+    } else {
+        Cow::Borrowed(tag)
+    };
+
+    let mut pos = 10;
+    if flags & 0x40 != 0 {
+        // Extended header, its size excludes the size field in 2.3 and includes it in 2.4
+        pos += match version {
+            3 => 4 + be_u32(&data, pos)? as usize,
+            4 => syncsafe_u32(&data, pos)? as usize,
+            _ => 0,
+        };
+    }
+    let header_len = if version == 2 { 6 } else { 10 };
+
+    while pos + header_len <= data.len() {
+        let (id, size) = if version == 2 {
+            let size = ((data[pos + 3] as usize) << 16) | ((data[pos + 4] as usize) << 8) | data[pos + 5] as usize;
+            (&data[pos..pos + 3], size)
+        } else if version == 4 {
+            (&data[pos..pos + 4], syncsafe_u32(&data, pos + 4)? as usize)
+        } else {
+            (&data[pos..pos + 4], be_u32(&data, pos + 4)? as usize)
+        };
+        if id[0] == 0 {
+            break; // Padding
+        }
+        let format_flags = if version == 2 { 0 } else { data[pos + 9] };
+        let mut body = Cow::Borrowed(data.get(pos + header_len..pos + header_len + size)?);
+        pos += header_len + size;
+
+        if id != b"APIC" && id != b"PIC" {
+            continue;
+        }
+        match version {
+            4 => {
+                if format_flags & 0x0C != 0 {
+                    continue; // Compressed or encrypted
+                }
+                if format_flags & 0x01 != 0 {
+                    body = Cow::Owned(body.get(4..)?.to_vec()); // Data length indicator
+                }
+                if format_flags & 0x02 != 0 || unsynchronised {
+                    body = Cow::Owned(resync(&body));
+                }
+            }
+            3 => {
+                if format_flags & 0xC0 != 0 {
+                    continue; // Compressed or encrypted
+                }
+                if format_flags & 0x20 != 0 {
+                    body = Cow::Owned(body.get(1..)?.to_vec()); // Group id
+                }
+            }
+            _ => (),
+        }
+
+        let encoding = *body.first()?;
+        // ID3v2.2 has a fixed three character image format instead of a mime type
+        let after_mime = if version == 2 { 4 } else { skip_id3_string(&body, 1, 0)? };
+        let after_description = skip_id3_string(&body, after_mime + 1, encoding)?;
+        return Some(body.get(after_description..)?.to_vec());
+    }
+    None
+}
+
+fn flac_artwork(file: &mut File) -> Option<Vec<u8>> {
+    let mut pos = 4;
+    loop {
+        let header = read_at(file, pos, 4)?;
+        let len = (be_u32(&header, 0)? & 0x00FF_FFFF) as usize;
+        if header[0] & 0x7F == 6 {
+            // Picture type, mime, description, width, height, depth, colours, data
+            let block = read_at(file, pos + 4, len)?;
+            let mut at = 4;
+            at += 4 + be_u32(&block, at)? as usize;
+            at += 4 + be_u32(&block, at)? as usize;
+            at += 16;
+            let len = be_u32(&block, at)? as usize;
+            return Some(block.get(at + 4..at + 4 + len)?.to_vec());
+        }
+        if header[0] & 0x80 != 0 {
+            return None; // Last block
+        }
+        pos += 4 + len as u64;
+    }
+}
+
+/// Contents of the top level moov atom, skipping over the audio data
+fn mp4_moov(file: &mut File) -> Option<Vec<u8>> {
+    let end = file.metadata().ok()?.len();
+    let mut pos = 0;
+    while pos + 8 <= end {
+        let header = read_at(file, pos, 16.min(end - pos) as usize)?;
+        let (size, header_len) = match be_u32(&header, 0)? {
+            0 => (end - pos, 8), // Extends to the end of the file
+            1 => (u64::from_be_bytes(header.get(8..16)?.try_into().ok()?), 16),
+            size => (size as u64, 8),
+        };
+        if size < header_len {
+            return None;
+        }
+        if &header[4..8] == b"moov" {
+            return read_at(file, pos + header_len, (size - header_len) as usize);
+        }
+        pos += size;
+    }
+    None
+}
+
+/// Find the first child atom of `kind` within `data`
+fn mp4_atom<'a>(data: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let size = be_u32(data, pos)? as usize;
+        if size < 8 {
+            return None;
+        }
+        if &data[pos + 4..pos + 8] == kind {
+            return data.get(pos + 8..pos + size);
+        }
+        pos += size;
+    }
+    None
+}
+
+fn mp4_artwork(moov: &[u8]) -> Option<Vec<u8>> {
+    let udta = mp4_atom(moov, b"udta")?;
+    // meta is a full atom with four bytes of version and flags before its children
+    let meta = mp4_atom(udta, b"meta")?.get(4..)?;
+    let ilst = mp4_atom(meta, b"ilst")?;
+    let covr = mp4_atom(ilst, b"covr")?;
+    // data atoms start with four bytes of type and four of locale
+    Some(mp4_atom(covr, b"data")?.get(8..)?.to_vec())
+}
@@ -1,10 +1,16 @@
 use crate::config::Config;
-use crate::log::ScopedLogger;
+use crate::control::{Command, Control};
+use crate::cues::Cue;
+use crate::events::{BeatCount, Bands, Event, EventSender, PlayState, Target};
+use crate::log::{Logger, ScopedLogger};
 use crate::memory::MemReader;
+use crate::memory::MemSource;
 use crate::memory::MemoryReadErrorType;
 use crate::memory::MemoryReadError;
+use crate::metadata::PdbResolver;
 use crate::outputmodules::ModuleDefinition;
 use crate::outputmodules::OutputModule;
+use crate::recorder::Recorder;
 use crate::utils::PhraseParser;
 use crate::RekordboxOffsets;
 use crate::memory::PointerChainValue;
@@ -12,9 +18,11 @@ use binrw::BinRead;
 use notify::Watcher;
 use rekordcrate::anlz::{self, BeatGrid};
 use std::io::Cursor;
-use std::sync::mpsc;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::memory::Value;
 
@@ -37,8 +45,8 @@ pub struct Rekordbox {
 }
 
 impl Rekordbox {
-    fn new(offsets: RekordboxOffsets, decks: usize) -> Result<Self, MemoryReadError> {
-        let mem = crate::memory::MemReader::new()?;
+    fn new(offsets: RekordboxOffsets, decks: usize, source: &MemSource) -> Result<Self, MemoryReadError> {
+        let mem = crate::memory::MemReader::new(source, &offsets, decks)?;
 
         let current_bpms = mem.new_values(&offsets.current_bpm[0..decks])?;
         let sample_positions = mem.new_values(&offsets.sample_position[0..decks])?;
@@ -75,29 +83,24 @@ impl Rekordbox {
         Ok(self.masterdeck_index.read(&self.mem)? as usize)
     }
 
-    fn get_track_infos(&self) -> Result<Vec<TrackInfo>, MemoryReadError> {
+    /// Track info text as it is in memory, up to the terminating null
+    fn read_raw_track_infos(&self) -> Result<Vec<Vec<u8>>, MemoryReadError> {
         (0..self.deckcount)
             .map(|i| {
-                let raw = self.track_infos[i]
+                Ok(self.track_infos[i]
                     .read(&self.mem)?
                     .into_iter()
                     .take_while(|x| *x != 0x00)
-                    .collect::<Vec<u8>>();
-                let text = String::from_utf8(raw).unwrap_or_else(|_| "ERR".to_string());
-                let mut lines = text
-                    .lines()
-                    .map(|x| x.split_once(": ").unwrap_or(("", "")).1)
-                    .map(|x| x.to_string());
-
-                Ok(TrackInfo {
-                    title: lines.next().unwrap_or("".to_string()),
-                    artist: lines.next().unwrap_or("".to_string()),
-                    album: lines.next().unwrap_or("".to_string()),
-                })
+                    .collect::<Vec<u8>>())
             })
             .collect()
     }
 
+    fn parse_track_info(raw: &[u8]) -> TrackInfo {
+        let text = String::from_utf8(raw.to_vec()).unwrap_or_else(|_| "ERR".to_string());
+        TrackInfo::parse(&text)
+    }
+
     fn get_anlz_paths(&self) -> Result<Vec<String>, MemoryReadError> {
         (0..self.deckcount)
             .map(|i| {
@@ -106,7 +109,8 @@ impl Rekordbox {
                     .into_iter()
                     .take_while(|x| *x != 0x00)
                     .collect::<Vec<u8>>();
-                Ok(String::from_utf8(raw).unwrap_or_else(|_| "ERR".to_string()))
+                let path = String::from_utf8(raw).unwrap_or_else(|_| "ERR".to_string());
+                Ok(self.mem.translate_path(path))
             })
             .collect()
     }
@@ -123,6 +127,20 @@ pub struct TrackInfo {
     pub title: String,
     pub artist: String,
     pub album: String,
+    pub key: String,
+    pub genre: String,
+    pub label: String,
+    /// Stars, 0-5
+    pub rating: u8,
+    pub comment: String,
+    /// Original tempo, only known from the export database
+    pub bpm: f32,
+    /// Seconds, only known from the export database
+    pub duration: f32,
+    /// Path to the artwork image, only known from the export database
+    pub artwork: String,
+    /// Path to the audio file, from the ANLZ file
+    pub file_path: String,
 }
 impl Default for TrackInfo {
     fn default() -> Self {
@@ -130,10 +148,97 @@ impl Default for TrackInfo {
             title: "".to_string(),
             artist: "".to_string(),
             album: "".to_string(),
+            key: "".to_string(),
+            genre: "".to_string(),
+            label: "".to_string(),
+            rating: 0,
+            comment: "".to_string(),
+            bpm: 0.,
+            duration: 0.,
+            artwork: "".to_string(),
+            file_path: "".to_string(),
         }
     }
 }
 
+impl TrackInfo {
+    /// Names accepted by `field`, in the order they are written by `to_text`
+    pub const FIELDS: [&'static str; 12] = ["title", "artist", "album", "key", "genre", "label", "rating", "comment", "bpm", "duration", "artwork", "file_path"];
+
+    /// Parse the `Name: value` lines of the track info text in Rekordbox memory.
+    /// Lines are matched by name, the first three fall back to title, artist and album
+    /// when the name is unknown, e.g. in a translated Rekordbox.
+    ///
+    /// Only title, artist and album are known to be in memory. The key, genre, label, rating and
+    /// comment lines are best-effort: they are picked up if Rekordbox has them within the 200 bytes
+    /// read, and otherwise stay empty unless the export database provides them.
+    pub fn parse(text: &str) -> Self {
+        let mut info = TrackInfo::default();
+        for (i, line) in text.lines().enumerate() {
+            let (name, value) = line.split_once(": ").unwrap_or(("", ""));
+            let value = value.to_string();
+            let mut name = name.trim().to_lowercase();
+            if i < 3 && name != "comments" && !Self::FIELDS.contains(&name.as_str()) {
+                name = Self::FIELDS[i].to_string();
+            }
+            match name.as_str() {
+                "title" => info.title = value,
+                "artist" => info.artist = value,
+                "album" => info.album = value,
+                "key" => info.key = value,
+                "genre" => info.genre = value,
+                "label" => info.label = value,
+                "rating" => {
+                    info.rating = value
+                        .trim()
+                        .parse()
+                        .unwrap_or_else(|_| value.chars().filter(|c| *c == '★' || *c == '*').count() as u8)
+                        .min(5)
+                }
+                "comment" | "comments" => info.comment = value,
+                "bpm" => info.bpm = value.trim().parse().unwrap_or(0.),
+                "duration" => info.duration = value.trim().parse().unwrap_or(0.),
+                "artwork" => info.artwork = value,
+                "file_path" => info.file_path = value,
+                _ => (),
+            }
+        }
+        info
+    }
+
+    /// Inverse of `parse`
+    pub fn to_text(&self) -> String {
+        Self::FIELDS
+            .iter()
+            .map(|name| {
+                let mut label = name.to_string();
+                label[..1].make_ascii_uppercase();
+                format!("{label}: {}", self.field(name).unwrap_or_default())
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Look up a field by name, for modules with configurable output
+    pub fn field(&self, name: &str) -> Option<String> {
+        Some(match name {
+            "title" => self.title.clone(),
+            "artist" => self.artist.clone(),
+            "album" => self.album.clone(),
+            "key" => self.key.clone(),
+            "genre" => self.genre.clone(),
+            "label" => self.label.clone(),
+            "rating" => self.rating.to_string(),
+            "comment" => self.comment.clone(),
+            "bpm" => self.bpm.to_string(),
+            "duration" => self.duration.to_string(),
+            "artwork" => self.artwork.clone(),
+            "file_path" => self.file_path.clone(),
+            _ => return None,
+        })
+    }
+}
+
 #[derive(Clone)]
 struct ChangeTrackedValue<T> {
     value: T,
@@ -168,46 +273,179 @@ pub struct BeatKeeper {
     last_error: Option<MemoryReadError>,
     keep_warm: bool,
     decks: usize,
+    recorder: Option<Recorder>,
+    metadata: Option<PdbResolver>,
+    control: Option<Control>,
+    masterdeck_override: Option<usize>,
 
 
     td_trackers: Vec<TrackingDataTracker>,
     master_td_tracker: TrackingDataTracker,
+
+    offsets: RekordboxOffsets,
+    source: MemSource,
+    rekordbox: Option<Rekordbox>,
+    rekordbox_decks: usize,
+    period: Duration,
+    slow_update_denominator: usize,
+    slow_update_counter: usize,
 }
 
 struct TrackingDataTracker {
     bpm_changed: ChangeTrackedValue<f32>,
     original_bpm_changed: ChangeTrackedValue<f32>,
     beat_changed: ChangeTrackedValue<f32>,
+    track_beat: ChangeTrackedValue<f32>,
+    phrase_beat: ChangeTrackedValue<f32>,
+    beat_count: ChangeTrackedValue<BeatCount>,
     pos_changed: ChangeTrackedValue<i64>,
+    length: ChangeTrackedValue<f32>,
+    remaining: ChangeTrackedValue<f32>,
+    progress: ChangeTrackedValue<f32>,
+    next_cue: ChangeTrackedValue<Option<Cue>>,
+    next_cue_in: ChangeTrackedValue<i32>,
+    energy: ChangeTrackedValue<f32>,
+    bands: ChangeTrackedValue<Option<Bands>>,
     phrase: ChangeTrackedValue<String>,
     next_phrase: ChangeTrackedValue<String>,
     next_phrase_in: ChangeTrackedValue<i32>,
+    play_state: ChangeTrackedValue<PlayState>,
 }
 
 impl TrackingDataTracker {
+    /// The current values as events, for state queries
+    fn snapshot(&self) -> Vec<Event> {
+        vec![
+            Event::Bpm(self.bpm_changed.value),
+            Event::OriginalBpm(self.original_bpm_changed.value),
+            Event::Beat(self.beat_changed.value),
+            Event::TrackBeat(self.track_beat.value),
+            Event::PhraseBeat(self.phrase_beat.value),
+            Event::BeatCount(self.beat_count.value),
+            Event::Time(self.pos_changed.value as f32 / 44100.),
+            Event::TrackLength(self.length.value),
+            Event::TimeRemaining(self.remaining.value),
+            Event::Progress(self.progress.value),
+            Event::NextCue(self.next_cue.value.clone()),
+            Event::NextCueIn(self.next_cue_in.value),
+            Event::Bands(self.bands.value.unwrap_or_default()),
+            Event::Energy(self.energy.value),
+            Event::Phrase(self.phrase.value.clone()),
+            Event::NextPhrase(self.next_phrase.value.clone()),
+            Event::NextPhraseIn(self.next_phrase_in.value),
+            Event::PlayState(self.play_state.value),
+        ]
+    }
+
+    /// Update with the latest tracker result, returning events for everything that changed
+    fn changes(&mut self, res: &TrackTrackerResult) -> Vec<Event> {
+        let mut events = vec![];
+        if self.beat_changed.set(res.beat) {
+            events.push(Event::Beat(res.beat));
+        }
+        if self.track_beat.set(res.track_beat) {
+            events.push(Event::TrackBeat(res.track_beat));
+        }
+        if self.phrase_beat.set(res.phrase_beat) {
+            events.push(Event::PhraseBeat(res.phrase_beat));
+        }
+        if self.beat_count.set(res.beat_count) {
+            events.push(Event::BeatCount(res.beat_count));
+        }
+        if self.pos_changed.set(res.timing_data_raw.sample_position) {
+            events.push(Event::Time(res.timing_data_raw.sample_position as f32 / 44100.));
+        }
+        if self.length.set(res.length) {
+            events.push(Event::TrackLength(res.length));
+        }
+        if self.remaining.set(res.remaining) {
+            events.push(Event::TimeRemaining(res.remaining));
+        }
+        if self.progress.set(res.progress) {
+            events.push(Event::Progress(res.progress));
+        }
+        if self.next_cue.set(res.next_cue.clone()) {
+            events.push(Event::NextCue(res.next_cue.clone()));
+        }
+        if self.next_cue_in.set(res.next_cue_in) {
+            events.push(Event::NextCueIn(res.next_cue_in));
+        }
+        if let Some(cue) = &res.passed_cue {
+            events.push(Event::CuePassed(cue.clone()));
+        }
+        // Bands first, so outputs sending on the energy change carry the bands of the same update
+        if self.bands.set(res.bands) {
+            events.push(Event::Bands(res.bands.unwrap_or_default()));
+        }
+        if self.energy.set(res.energy) {
+            events.push(Event::Energy(res.energy));
+        }
+        if self.bpm_changed.set(res.timing_data_raw.current_bpm) {
+            events.push(Event::Bpm(res.timing_data_raw.current_bpm));
+        }
+        if self.original_bpm_changed.set(res.original_bpm) {
+            events.push(Event::OriginalBpm(res.original_bpm));
+        }
+        if self.phrase.set(res.phrase.clone()) {
+            events.push(Event::Phrase(res.phrase.clone()));
+        }
+        if self.next_phrase.set(res.next_phrase.clone()) {
+            events.push(Event::NextPhrase(res.next_phrase.clone()));
+        }
+        if self.next_phrase_in.set(res.next_phrase_in) {
+            events.push(Event::NextPhraseIn(res.next_phrase_in));
+        }
+        if self.play_state.set(res.play_state) {
+            events.push(Event::PlayState(res.play_state));
+        }
+        events
+    }
+
     fn new() -> Self {
         Self {
             bpm_changed: ChangeTrackedValue::new(0.),
             original_bpm_changed: ChangeTrackedValue::new(0.),
             beat_changed: ChangeTrackedValue::new(0.),
+            track_beat: ChangeTrackedValue::new(0.),
+            phrase_beat: ChangeTrackedValue::new(0.),
+            beat_count: ChangeTrackedValue::new(BeatCount::default()),
             pos_changed: ChangeTrackedValue::new(0),
+            length: ChangeTrackedValue::new(0.),
+            remaining: ChangeTrackedValue::new(0.),
+            progress: ChangeTrackedValue::new(0.),
+            next_cue: ChangeTrackedValue::new(None),
+            next_cue_in: ChangeTrackedValue::new(0),
+            energy: ChangeTrackedValue::new(0.),
+            bands: ChangeTrackedValue::new(None),
             phrase: ChangeTrackedValue::new("".to_string()),
             next_phrase: ChangeTrackedValue::new("".to_string()),
             next_phrase_in: ChangeTrackedValue::new(0),
+            play_state: ChangeTrackedValue::new(PlayState::Paused),
         }
     }
 }
 
 impl BeatKeeper {
-    pub fn start(
+    /// Set up the keeper and all enabled modules. Nothing is read from Rekordbox until `poll` or `run` is called.
+    pub fn new(
         offsets: RekordboxOffsets,
         modules: Vec<ModuleDefinition>,
         config: Config,
         logger: ScopedLogger,
-    ) {
+    ) -> Result<Self, ()> {
         let keeper_config = config.reduce_to_namespace("keeper");
         let update_rate = keeper_config.get_or_default("update_rate", 50);
-        let slow_update_denominator = keeper_config.get_or_default("slow_update_every_nth", 50);
+        let source = match keeper_config.get_or_default("source", "rekordbox".to_string()).as_str() {
+            "replay" => MemSource::Replay {
+                path: keeper_config.get_or_default("replay_file", "replay.txt".to_string()),
+                looping: keeper_config.get_or_default("replay_loop", true),
+            },
+            "rekordbox" => MemSource::Rekordbox,
+            other => {
+                logger.err(&format!("Unknown source '{other}', reading from Rekordbox"));
+                MemSource::Rekordbox
+            }
+        };
 
         let mut running_modules = vec![];
 
@@ -234,16 +472,43 @@ impl BeatKeeper {
             Ok(w) => w,
             Err(e) => {
                 logger.err(&format!("Failed to create watcher: {e}"));
-                return;
+                return Err(());
             }
         };
 
-        let mut keeper = BeatKeeper {
+        let recorder = if keeper_config.get_or_default("record", false) {
+            Recorder::new(
+                &keeper_config.get_or_default("record_file", "session_{time}.txt".to_string()),
+                ScopedLogger::new(&logger.logger, "Recorder"),
+            )
+        } else {
+            None
+        };
+
+        let metadata = if keeper_config.get_or_default("pdb_metadata", false) {
+            Some(PdbResolver::new(ScopedLogger::new(&logger.logger, "Metadata")))
+        } else {
+            None
+        };
+
+        let control = if config.get_or_default("control.enabled", false) {
+            Control::new(&config.reduce_to_namespace("control"), ScopedLogger::new(&logger.logger, "Control"))
+        } else {
+            None
+        };
+
+        if let MemSource::Replay { path, .. } = &source {
+            logger.info(&format!("Replaying timeline from {path}"));
+        }
+        logger.info("Looking for Rekordbox...");
+        logger.blank();
+
+        Ok(BeatKeeper {
             masterdeck_index: ChangeTrackedValue::new(0),
             offset_samples: (keeper_config.get_or_default("delay_compensation", 0.) * 44100. / 1000.) as i64,
             track_infos: vec![ChangeTrackedValue::new(Default::default()); 4],
             running_modules,
-            logger: logger.clone(),
+            logger,
             last_error: None,
             track_trackers: (0..4).map(|_| TrackTracker::new()).collect(),
             keep_warm: keeper_config.get_or_default("keep_warm", true),
@@ -253,70 +518,173 @@ impl BeatKeeper {
             anlz_paths: vec![ChangeTrackedValue::new("".to_string()); 4],
             watcher,
             watcher_rx,
+            recorder,
+            metadata,
+            control,
+            masterdeck_override: None,
+
+            offsets,
+            source,
+            rekordbox: None,
+            rekordbox_decks: config.get_or_default("keeper.decks", 2),
+            period: Duration::from_micros(1000000 / update_rate), // 50Hz
+            slow_update_denominator: keeper_config.get_or_default("slow_update_every_nth", 50),
+            slow_update_counter: 0,
+        })
+    }
+
+    /// Run the keeper on the current thread until Ctrl-C is pressed, then drop the modules so they
+    /// can shut down cleanly, such as sACN terminating its streams.
+    pub fn start(
+        offsets: RekordboxOffsets,
+        modules: Vec<ModuleDefinition>,
+        config: Config,
+        logger: ScopedLogger,
+    ) {
+        let Ok(mut keeper) = BeatKeeper::new(offsets, modules, config, logger) else {
+            return;
         };
 
-        let mut rekordbox = None;
+        let stop = Arc::new(AtomicBool::new(false));
+        let handler_stop = stop.clone();
+        if let Err(e) = ctrlc::set_handler(move || handler_stop.store(true, Ordering::Relaxed)) {
+            keeper.logger.warn(&format!("Failed to set Ctrl-C handler, modules won't be shut down on exit: {e}"));
+        }
 
-        let period = Duration::from_micros(1000000 / update_rate); // 50Hz
-        let mut n = 0;
+        while !stop.load(Ordering::Relaxed) {
+            let deadline = Instant::now() + keeper.poll();
+            keeper.wait_until(deadline, &stop);
+        }
 
-        logger.info("Looking for Rekordbox...");
-        println!();
+        keeper.logger.info("Shutting down...");
+        keeper.running_modules.clear();
+    }
+
+    /// Run the keeper on a new thread, returning a stream of its events.
+    /// `config` is the contents of a config file, since the parsed config can't be sent across threads.
+    pub fn spawn(
+        offsets: RekordboxOffsets,
+        modules: Vec<ModuleDefinition>,
+        config: String,
+        debug: bool,
+    ) -> (thread::JoinHandle<()>, mpsc::Receiver<(Target, Event)>) {
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let logger = Rc::new(Logger::new(debug));
+            let config = Config::parse(&config, ScopedLogger::new(&logger, "Config"));
+            if let Ok(mut keeper) = BeatKeeper::new(offsets, modules, config, ScopedLogger::new(&logger, "BeatKeeper")) {
+                keeper.running_modules.push(Box::new(EventSender::new(tx)));
+                keeper.run();
+            }
+        });
+        (handle, rx)
+    }
 
+    /// Receive all events emitted by the keeper from now on
+    pub fn subscribe(&mut self) -> mpsc::Receiver<(Target, Event)> {
+        let (tx, rx) = mpsc::channel();
+        self.running_modules.push(Box::new(EventSender::new(tx)));
+        rx
+    }
+
+    pub fn run(&mut self) -> ! {
+        let stop = AtomicBool::new(false);
         loop {
-            if let Some(rb) = &rekordbox {
-                let update_start_time = std::time::Instant::now();
-                if let Err(e) = keeper.update(rb, n == 0) {
-                    keeper.report_error(e);
-
-                    rekordbox = None;
-                    logger.err("Connection to Rekordbox lost");
-                    logger.info("Reconnecting in 3s...");
-                    thread::sleep(Duration::from_secs(3));
-                } else {
-                    n = (n + 1) % slow_update_denominator;
-                    let elapsed = update_start_time.elapsed();
-                    if period > elapsed {
-                        thread::sleep(period - elapsed);
-                    }
-                }
-            } else {
-                match Rekordbox::new(offsets.clone(), config.get_or_default("keeper.decks", 2)) {
-                    Ok(rb) => {
-                        rekordbox = Some(rb);
-                        println!();
-                        logger.good("Connected to Rekordbox!");
-                        keeper.last_error = None;
-                    }
-                    Err(e) => {
-                        keeper.report_error(e);
-                        logger.info("...");
-                        thread::sleep(Duration::from_secs(3));
-                    }
-                }
+            let deadline = Instant::now() + self.poll();
+            self.wait_until(deadline, &stop);
+        }
+    }
+
+    /// Sleep until `deadline` or until `stop` is set. Sleeps in short steps and handles commands in
+    /// between, since the wait is a few seconds while not connected to Rekordbox.
+    fn wait_until(&mut self, deadline: Instant, stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return;
             }
+            thread::sleep(remaining.min(Duration::from_millis(50)));
+            self.handle_commands();
         }
     }
 
-    
+    /// Perform a single update, or a connection attempt if not connected to Rekordbox.
+    /// Returns how long to wait before polling again.
+    pub fn poll(&mut self) -> Duration {
+        self.handle_commands();
+
+        let Some(rb) = self.rekordbox.take() else {
+            return match Rekordbox::new(self.offsets.clone(), self.rekordbox_decks, &self.source) {
+                Ok(rb) => {
+                    self.rekordbox = Some(rb);
+                    self.logger.blank();
+                    self.logger.good("Connected to Rekordbox!");
+                    self.last_error = None;
+                    Duration::ZERO
+                }
+                Err(e) => {
+                    self.report_error(e);
+                    self.logger.info("...");
+                    Duration::from_secs(3)
+                }
+            };
+        };
+
+        let update_start_time = Instant::now();
+        if let Err(e) = self.update(&rb, self.slow_update_counter == 0) {
+            self.report_error(e);
+
+            self.logger.err("Connection to Rekordbox lost");
+            self.logger.info("Reconnecting in 3s...");
+            return Duration::from_secs(3);
+        }
+        self.rekordbox = Some(rb);
+        self.slow_update_counter = (self.slow_update_counter + 1) % self.slow_update_denominator;
+        self.period.saturating_sub(update_start_time.elapsed())
+    }
 
     fn update(
         &mut self,
         rb: &Rekordbox,
         slow_update: bool,
+    ) -> Result<(), MemoryReadError> {
+        for module in &mut self.running_modules {
+            module.pre_update();
+        }
+        let result = self.read_and_emit(rb, slow_update);
+        // Also after an early return, so modules batching their output don't carry it into the next update
+        for module in &mut self.running_modules {
+            module.post_update();
+        }
+        result
+    }
+
+    fn read_and_emit(
+        &mut self,
+        rb: &Rekordbox,
+        slow_update: bool,
     ) -> Result<(), MemoryReadError> {
         // let masterdeck_index_changed = self.masterdeck_index.set(td.masterdeck_index as usize);
-        let masterdeck_index_changed = self.masterdeck_index.set(rb.read_masterdeck_index()?);
+        // Sessions hold the master deck Rekordbox reports, so it is read even while overridden when recording
+        let reported_masterdeck = if self.masterdeck_override.is_none() || self.recorder.is_some() {
+            Some(rb.read_masterdeck_index()?)
+        } else {
+            None
+        };
+        if let (Some(recorder), Some(deck)) = (&mut self.recorder, reported_masterdeck) {
+            recorder.masterdeck(deck);
+        }
+        let masterdeck_index = self.masterdeck_override.or(reported_masterdeck).unwrap_or_default();
+        let masterdeck_index_changed = self.masterdeck_index.set(masterdeck_index);
         if self.masterdeck_index.value >= rb.deckcount {
             return Ok(()); // No master deck selected - rekordbox is not initialised
         }
+        if masterdeck_index_changed {
+            emit(&mut self.running_modules, Target::Master, &[Event::MasterDeck(self.masterdeck_index.value)]);
+        }
 
         // let mut tracker_data = None;
 
-        for module in &mut self.running_modules {
-            module.pre_update();
-        }
-
         for (i, (tracker, td_tracker)) in (self.track_trackers[0..self.decks])
             .iter_mut()
             .zip(self.td_trackers[0..self.decks].iter_mut())
@@ -329,92 +697,16 @@ impl BeatKeeper {
                 let Ok(res) = res else {
                     continue;
                 };
-
-                let bpm_changed = td_tracker.bpm_changed.set(res.timing_data_raw.current_bpm);
-                let original_bpm_changed = td_tracker.original_bpm_changed.set(res.original_bpm);
-                let beat_changed = td_tracker.beat_changed.set(res.beat);
-                let pos_changed = td_tracker.pos_changed.set(res.timing_data_raw.sample_position);
-                let phrase_changed = td_tracker.phrase.set(res.phrase.clone());
-                let next_phrase_changed = td_tracker.next_phrase.set(res.next_phrase.clone());
-                let next_phrase_in_changed = td_tracker.next_phrase_in.set(res.next_phrase_in);
-
-                for module in &mut self.running_modules {
-                    if beat_changed {
-                        module.beat_update(res.beat, i);
-                    }
-                    if pos_changed {
-                        module.time_update(res.timing_data_raw.sample_position as f32 / 44100., i);
-                    }
-                    if bpm_changed {
-                        module.bpm_changed(res.timing_data_raw.current_bpm, i);
-                    }
-                    if original_bpm_changed {
-                        module.original_bpm_changed(res.original_bpm, i);
-                    }
-                    if phrase_changed {
-                        module.phrase_changed(&res.phrase, i);
-                    }
-                    if next_phrase_changed {
-                        module.next_phrase_changed(&res.next_phrase, i);
-                    }
-                    if next_phrase_in_changed {
-                        module.next_phrase_in(res.next_phrase_in, i);
-                    }
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.timing(i, res.bpm_read, res.timing_data_raw.sample_position);
                 }
 
-                if is_master {
-                    let bpm_changed = self
-                        .master_td_tracker
-                        .bpm_changed
-                        .set(res.timing_data_raw.current_bpm);
-                    let original_bpm_changed = self
-                        .master_td_tracker
-                        .original_bpm_changed
-                        .set(res.original_bpm);
-                    let beat_changed = self.master_td_tracker.beat_changed.set(res.beat);
-                    let pos_changed = self
-                        .master_td_tracker
-                        .pos_changed
-                        .set(res.timing_data_raw.sample_position);
-                    let phrase_changed = self
-                        .master_td_tracker
-                        .phrase
-                        .set(res.phrase);
-                    let next_phrase_changed = self
-                        .master_td_tracker
-                        .next_phrase
-                        .set(res.next_phrase);
-                    let next_phrase_in_changed = self
-                        .master_td_tracker
-                        .next_phrase_in
-                        .set(res.next_phrase_in);
-
+                let events = td_tracker.changes(&res);
+                emit(&mut self.running_modules, Target::Deck(i), &events);
 
-                    for module in &mut self.running_modules {
-                        if beat_changed {
-                            module.beat_update_master(res.beat);
-                        }
-                        if pos_changed {
-                            module.time_update_master(
-                                res.timing_data_raw.sample_position as f32 / 44100.,
-                            );
-                        }
-                        if bpm_changed {
-                            module.bpm_changed_master(res.timing_data_raw.current_bpm);
-                        }
-                        if original_bpm_changed {
-                            module.original_bpm_changed_master(res.original_bpm);
-                        }
-                        if phrase_changed {
-                            module.phrase_changed_master(&self.master_td_tracker.phrase.value);
-                        }
-                        if next_phrase_changed {
-                            module.next_phrase_changed_master(&self.master_td_tracker.next_phrase.value);
-                        }
-                        if next_phrase_in_changed {
-                            module.next_phrase_in_master(res.next_phrase_in);
-                        }
-                    }
+                if is_master {
+                    let events = self.master_td_tracker.changes(&res);
+                    emit(&mut self.running_modules, Target::Master, &events);
                 }
             }
         }
@@ -423,15 +715,24 @@ impl BeatKeeper {
         let mut masterdeck_track_changed = false;
 
         if slow_update {
-            for (i, track) in rb.get_track_infos()?.into_iter().enumerate() {
-                if self.track_infos[i].set(track) {
-                    for module in &mut self.running_modules {
-                        module.track_changed(&self.track_infos[i].value, i);
+            // With the export database as a fallback, a broken track info pointer chain is not fatal
+            let track_infos = match rb.read_raw_track_infos() {
+                Ok(raw) => {
+                    if let Some(recorder) = &mut self.recorder {
+                        for (i, raw) in raw.iter().enumerate() {
+                            recorder.track_info(i, raw);
+                        }
                     }
-                    self.track_trackers[i].track_changed = true;
-                    masterdeck_track_changed |= self.masterdeck_index.value == i;
+                    raw.iter().map(|raw| Rekordbox::parse_track_info(raw)).collect()
                 }
-            }
+                Err(e) if self.metadata.is_some() => {
+                    if let Some(p) = &e.pointer {
+                        self.logger.debug(&format!("Failed to read track info from memory, pointer: {p}"));
+                    }
+                    vec![TrackInfo::default(); rb.deckcount]
+                }
+                Err(e) => return Err(e),
+            };
 
 
             let mut anlz_file_updates = [false; 4];
@@ -452,6 +753,9 @@ impl BeatKeeper {
             }
 
             for (i, path) in rb.get_anlz_paths()?.into_iter().enumerate() {
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.anlz_path(i, &path);
+                }
                 if self.anlz_paths[i].value != path || anlz_file_updates[i] {
                     if self.anlz_paths[i].value != path {
                         self.logger.debug(&format!("Deck {i} ANLZ file path changed: {path}"));
@@ -485,6 +789,11 @@ impl BeatKeeper {
                         self.logger.err("If you are loading a new streaming track for the first time, eject and load it again.");
                         continue;
                     };
+                    self.track_trackers[i].length = None;
+                    self.track_trackers[i].cues.clear();
+                    self.track_trackers[i].file_path.clear();
+                    self.track_trackers[i].waveform.clear();
+                    self.track_trackers[i].waveform_bands.clear();
                     let mut reader = Cursor::new(bytes);
                     let anlz = match rekordcrate::anlz::ANLZ::read(&mut reader){
                         Ok(a) => a,
@@ -494,14 +803,20 @@ impl BeatKeeper {
                         }
                     };
                     for section in anlz.sections {
-                        #[allow(clippy::single_match)]
                         match section.content {
                             anlz::Content::BeatGrid(grid) => {
                                 self.track_trackers[i].beatgrid = Some(grid);
                             }
+                            anlz::Content::CueList(list) => {
+                                self.track_trackers[i].cues.extend(Cue::from_cue_list(&list));
+                            }
+                            anlz::Content::Path(path) => {
+                                self.track_trackers[i].file_path = path.path.to_string().trim_end_matches('\0').replace('\\', "/");
+                            }
                             _ => (),
                         }
                     }
+                    self.track_trackers[i].cues.sort_by(|a, b| a.time.total_cmp(&b.time));
 
                     let bytes = match std::fs::read(self.anlz_paths[i].value.replace(".DAT", ".EXT")) {
                         Ok(b) => b,
@@ -511,6 +826,7 @@ impl BeatKeeper {
                         }
                     };
 
+                    let mut ext_cues = vec![];
                     let mut reader = Cursor::new(bytes);
                     let anlz = match rekordcrate::anlz::ANLZ::read(&mut reader) {
                         Ok(a) => a,
@@ -520,33 +836,123 @@ impl BeatKeeper {
                         }
                     };
                     for section in anlz.sections {
-                        #[allow(clippy::single_match)]
                         match section.content {
                             anlz::Content::SongStructure(phrases) => {
                                 self.track_trackers[i].songstructure = Some(phrases.data);
                             }
+                            anlz::Content::WaveformDetail(waveform) => {
+                                // The detail waveform has one entry per half frame, 150 per second, for the whole track
+                                self.track_trackers[i].length = Some(waveform.data.len() as f32 / 150.);
+                                // Heights are 5 bit
+                                if self.track_trackers[i].waveform.is_empty() {
+                                    self.track_trackers[i].waveform = waveform.data.iter().map(|c| c.height() as f32 / 31.).collect();
+                                }
+                            }
+                            anlz::Content::WaveformColorDetail(waveform) => {
+                                // Same resolution as the detail waveform. Colour channels are 3 bit and roughly
+                                // follow the energy in the low, mid and high frequencies
+                                self.track_trackers[i].waveform = waveform.data.iter().map(|c| c.height() as f32 / 31.).collect();
+                                self.track_trackers[i].waveform_bands = waveform
+                                    .data
+                                    .iter()
+                                    .map(|c| Bands {
+                                        low: c.red() as f32 / 7.,
+                                        mid: c.green() as f32 / 7.,
+                                        high: c.blue() as f32 / 7.,
+                                    })
+                                    .collect();
+                            }
+                            anlz::Content::ExtendedCueList(list) => {
+                                ext_cues.extend(Cue::from_extended_cue_list(&list));
+                            }
                             _ => (),
                         }
                     }
+                    // The extended lists hold the same cues plus colours and comments
+                    if !ext_cues.is_empty() {
+                        ext_cues.sort_by(|a, b| a.time.total_cmp(&b.time));
+                        self.track_trackers[i].cues = ext_cues;
+                    }
                 }
             }
+
+            // Track info is handled after the ANLZ paths, which the export lookup needs
+            for (i, mut track) in track_infos.into_iter().enumerate() {
+                track.file_path = self.track_trackers[i].file_path.clone();
+                if let Some(metadata) = &mut self.metadata {
+                    metadata.enrich(&self.anlz_paths[i].value, &mut track);
+                }
+                if self.track_infos[i].set(track) {
+                    emit(&mut self.running_modules, Target::Deck(i), &[Event::Track(self.track_infos[i].value.clone())]);
+                    self.track_trackers[i].track_changed = true;
+                    masterdeck_track_changed |= self.masterdeck_index.value == i;
+                }
+            }
+
             for module in &mut self.running_modules {
                 module.slow_update();
             }
+            if let Some(recorder) = &mut self.recorder {
+                recorder.flush();
+            }
         }
 
         if masterdeck_index_changed || masterdeck_track_changed {
             let track = &self.track_infos[self.masterdeck_index.value].value;
             self.logger
                 .debug(&format!("Master track changed: {track:?}"));
-            for module in &mut self.running_modules {
-                module.track_changed_master(track);
-            }
+            emit(&mut self.running_modules, Target::Master, &[Event::Track(track.clone())]);
         }
 
         Ok(())
     }
 
+    fn handle_commands(&mut self) {
+        let Some(control) = &self.control else {
+            return;
+        };
+        for (from, command) in control.commands() {
+            match command {
+                Command::Query => {
+                    let mut state = vec![(Target::Master, Event::MasterDeck(self.masterdeck_index.value))];
+                    for (i, td_tracker) in self.td_trackers[0..self.decks].iter().enumerate() {
+                        state.extend(td_tracker.snapshot().into_iter().map(|e| (Target::Deck(i), e)));
+                        state.push((Target::Deck(i), Event::Track(self.track_infos[i].value.clone())));
+                    }
+                    state.extend(self.master_td_tracker.snapshot().into_iter().map(|e| (Target::Master, e)));
+                    if let Some(track) = self.track_infos.get(self.masterdeck_index.value) {
+                        state.push((Target::Master, Event::Track(track.value.clone())));
+                    }
+                    let delay_compensation = self.offset_samples as f32 * 1000. / 44100.;
+                    control.reply(from, delay_compensation, self.masterdeck_override, &state);
+                }
+                Command::DelayCompensation(ms) => {
+                    self.logger.info(&format!("Delay compensation set to {ms}ms"));
+                    self.offset_samples = (ms * 44100. / 1000.) as i64;
+                    for module in &mut self.running_modules {
+                        module.delay_compensation_changed(ms);
+                    }
+                }
+                Command::Resync => {
+                    self.logger.info("Resyncing");
+                    for module in &mut self.running_modules {
+                        module.resync();
+                    }
+                }
+                Command::MasterDeckOverride(Some(deck)) if deck >= self.decks => {
+                    self.logger.warn(&format!("Can't use deck {deck} as master, there are only {} decks", self.decks));
+                }
+                Command::MasterDeckOverride(deck) => {
+                    match deck {
+                        Some(deck) => self.logger.info(&format!("Master deck pinned to deck {deck}")),
+                        None => self.logger.info("Master deck follows Rekordbox again"),
+                    }
+                    self.masterdeck_override = deck;
+                }
+            }
+        }
+    }
+
     fn report_error(&mut self, e: MemoryReadError) {
         if let Some(last) = &self.last_error {
             if e == *last {
@@ -585,6 +991,10 @@ impl BeatKeeper {
                 self.logger.err(&format!("Module not found: {detail}"));
                 self.logger.err("This new exciting error has never been seen before! Please report this on GitHub!");
             }
+            MemoryReadErrorType::ReplayFileInvalid => {
+                self.logger.err(&format!("Could not load replay file{detail}"));
+                self.logger.info("    Check keeper.replay_file in the config");
+            }
         };
         if let Some(p) = &e.pointer {
             self.logger.debug(&format!("Pointer: {p}"));
@@ -596,19 +1006,114 @@ impl BeatKeeper {
     }
 }
 
+fn emit(modules: &mut [Box<dyn OutputModule>], target: Target, events: &[Event]) {
+    for module in modules {
+        for event in events {
+            module.on_event(target, event);
+        }
+    }
+}
+
 struct TrackTrackerResult {
     beat: f32,
+    track_beat: f32,
+    phrase_beat: f32,
+    beat_count: BeatCount,
     original_bpm: f32,
+    play_state: PlayState,
+    length: f32,
+    remaining: f32,
+    progress: f32,
+    next_cue: Option<Cue>,
+    next_cue_in: i32,
+    passed_cue: Option<Cue>,
+    energy: f32,
+    bands: Option<Bands>,
     timing_data_raw: TimingDataRaw,
+    /// Tempo as read from memory, before substituting a default for decks without one
+    bpm_read: f32,
     phrase: String,
     next_phrase: String,
     next_phrase_in: i32,
 }
 
+// Rekordbox updates the sample position in audio buffer sized steps, so the speed is measured
+// over a window spanning several updates instead of between consecutive reads
+const TRANSPORT_WINDOW: Duration = Duration::from_millis(60);
+
+// Faster than this is a hot cue, seek or loop jump rather than a scratch
+const JUMP_SPEED: f32 = 4.;
+
+// Windows in a row outside the pitch range before a deck counts as scratching
+const SCRATCH_WINDOWS: u8 = 2;
+
+// Waveform columns on each side of the playhead to average energy over, 2 columns is ~13ms
+const WAVEFORM_WINDOW: usize = 2;
+
+struct TransportTracker {
+    window_start: Option<(Instant, i64)>,
+    state: PlayState,
+    scratch_windows: u8,
+}
+
+impl TransportTracker {
+    fn new() -> Self {
+        Self {
+            window_start: None,
+            state: PlayState::Paused,
+            scratch_windows: 0,
+        }
+    }
+
+    /// `rate` is the expected playback speed, current over original tempo
+    fn update(&mut self, sample_position: i64, rate: f32) -> PlayState {
+        let now = Instant::now();
+        let Some((start, start_position)) = self.window_start else {
+            self.window_start = Some((now, sample_position));
+            return self.state;
+        };
+        let elapsed = now - start;
+        if elapsed < TRANSPORT_WINDOW {
+            return self.state;
+        }
+        self.window_start = Some((now, sample_position));
+
+        let expected = elapsed.as_secs_f32() * 44100. * rate;
+        let speed = (sample_position - start_position) as f32 / expected;
+        if speed.abs() > JUMP_SPEED {
+            // A jump says nothing about whether the deck is playing, keep the state from before it
+            return self.state;
+        }
+        if speed.abs() < 0.05 {
+            self.state = PlayState::Paused;
+        } else if (speed - 1.).abs() < 0.1 {
+            self.state = PlayState::Playing;
+        } else if (speed + 1.).abs() < 0.1 {
+            self.state = PlayState::Reverse;
+        } else {
+            // Nudging the jog wheel or a tempo change shows up as a single window out of range
+            self.scratch_windows = self.scratch_windows.saturating_add(1);
+            if self.scratch_windows >= SCRATCH_WINDOWS {
+                self.state = PlayState::Scratching;
+            }
+            return self.state;
+        }
+        self.scratch_windows = 0;
+        self.state
+    }
+}
+
 struct TrackTracker {
     track_changed: bool, // External flag to indicate that the track has changed
     beatgrid: Option<BeatGrid>,
     songstructure: Option<rekordcrate::anlz::SongStructureData>,
+    length: Option<f32>, // Seconds, from the detail waveform
+    cues: Vec<Cue>, // Sorted by time
+    last_time: f32,
+    file_path: String,
+    waveform: Vec<f32>, // Amplitude 0..1, 150 entries per second
+    waveform_bands: Vec<Bands>, // Same resolution as waveform, empty without a colour waveform
+    transport: TransportTracker,
 }
 
 impl TrackTracker {
@@ -617,6 +1122,13 @@ impl TrackTracker {
             track_changed: false,
             beatgrid: None,
             songstructure: None,
+            length: None,
+            cues: vec![],
+            last_time: 0.,
+            file_path: String::new(),
+            waveform: vec![],
+            waveform_bands: vec![],
+            transport: TransportTracker::new(),
         }
     }
 
@@ -627,6 +1139,7 @@ impl TrackTracker {
         deck: usize,
     ) -> Result<TrackTrackerResult, MemoryReadError> {
         let mut td = rb.read_timing_data(deck)?;
+        let bpm_read = td.current_bpm;
         if td.current_bpm == 0.0 {
             td.current_bpm = 120.0;
         }
@@ -634,19 +1147,31 @@ impl TrackTracker {
         
 
         let mut beat = 0.0;
+        let mut beat_fraction = 0.0;
         let mut original_bpm = 120.0;
 
         let time_now = (td.sample_position + offset_samples) as f32 / 44100.;
         let mut beat_idx: usize = 0;
+        let mut beat_count = BeatCount::default();
         if let Some(grid) = &self.beatgrid {
+            let mut bars = 0;
             for gridbeat in grid.beats.iter() {
                 if gridbeat.time as f32 / 1000. >= time_now {
                     break;
                 }
+                if gridbeat.beat_number == 1 {
+                    bars += 1;
+                }
                 beat_idx += 1;
             }
             beat_idx = beat_idx.saturating_sub(1);
             let gridbeat = &grid.beats[beat_idx];
+            beat_count = BeatCount {
+                beat: beat_idx as i32 + 1,
+                bar: bars,
+                beat_in_bar: gridbeat.beat_number as i32,
+                total_beats: grid.beats.len() as i32,
+            };
             // println!("{} - {}", time, time_now);
             let remainder = time_now - gridbeat.time as f32 / 1000.;
             original_bpm = gridbeat.tempo as f32 / 100.0;
@@ -654,21 +1179,89 @@ impl TrackTracker {
 
             let b = (gridbeat.beat_number + 3) % 4;
             // println!("{b} {idx}");
-            beat = b as f32 + remainder / spb;
+            beat_fraction = remainder / spb;
+            beat = b as f32 + beat_fraction;
         }
 
 
         let beat_num = beat_idx + 1;
 
+        // Without a beatgrid the original tempo is unknown, assume no pitch
+        let rate = if self.beatgrid.is_some() { td.current_bpm / original_bpm } else { 1.0 };
+        let play_state = self.transport.update(td.sample_position, rate);
+
+        // Fall back to the end of the last beat if the EXT file had no detail waveform
+        let length = self.length.or_else(|| {
+            let last = self.beatgrid.as_ref()?.beats.last()?;
+            Some(last.time as f32 / 1000. + 60. / (last.tempo as f32 / 100.))
+        }).unwrap_or(0.);
+        let time = td.sample_position as f32 / 44100.;
+        let (remaining, progress) = if length > 0. {
+            ((length - time).max(0.), (time / length).clamp(0., 1.))
+        } else {
+            (0., 0.)
+        };
+
         let mut tout = TrackTrackerResult {
             beat,
+            track_beat: beat_idx as f32 + beat_fraction,
+            phrase_beat: 0.,
+            beat_count,
             original_bpm,
+            play_state,
+            length,
+            remaining,
+            progress,
+            next_cue: None,
+            next_cue_in: 0,
+            passed_cue: None,
+            energy: 0.,
+            bands: None,
             timing_data_raw: td,
+            bpm_read,
             phrase: "".to_string(),
             next_phrase: "".to_string(),
             next_phrase_in: 0,
         };
 
+        if let Some(cue) = self.cues.iter().find(|cue| cue.time > time_now) {
+            tout.next_cue_in = if let Some(grid) = &self.beatgrid {
+                let cue_beat = grid.beats.iter().take_while(|b| b.time as f32 / 1000. <= cue.time).count();
+                cue_beat as i32 - beat_num as i32
+            } else {
+                ((cue.time - time_now) * tout.timing_data_raw.current_bpm / 60.).ceil() as i32
+            };
+            tout.next_cue = Some(cue.clone());
+        }
+        // Only count cues passed during normal playback, jumps and loops would fire every cue in between
+        if time_now > self.last_time && time_now - self.last_time < 1. {
+            tout.passed_cue = self
+                .cues
+                .iter()
+                .rev()
+                .find(|cue| cue.time > self.last_time && cue.time <= time_now)
+                .cloned();
+        }
+        self.last_time = time_now;
+
+        // Averaged over a few columns, single columns flicker a lot
+        if !self.waveform.is_empty() {
+            let center = (time_now.max(0.) * 150.) as usize;
+            let range = center.saturating_sub(WAVEFORM_WINDOW)..(center + WAVEFORM_WINDOW + 1).min(self.waveform.len());
+            if !range.is_empty() {
+                let n = range.len() as f32;
+                tout.energy = self.waveform[range.clone()].iter().sum::<f32>() / n;
+                if self.waveform_bands.len() == self.waveform.len() {
+                    let bands = &self.waveform_bands[range];
+                    tout.bands = Some(Bands {
+                        low: bands.iter().map(|b| b.low).sum::<f32>() / n,
+                        mid: bands.iter().map(|b| b.mid).sum::<f32>() / n,
+                        high: bands.iter().map(|b| b.high).sum::<f32>() / n,
+                    });
+                }
+            }
+        }
+
         let mut phrase_idx: usize = 0;
         if let Some(songstructure) = &self.songstructure {
             // println!("Song structure: {:?}", songstructure);
@@ -683,6 +1276,7 @@ impl TrackTracker {
             // println!("{phrase_idx} {beat_idx} {:?}", &songstructure.phrases[phrase_idx].kind);
             // println!("Phrase: {beat_num} {}", rb.phraseparser.get_phrase_name(&songstructure.mood, &songstructure.phrases[phrase_idx]));
             tout.phrase = rb.phraseparser.get_phrase_name(&songstructure.mood, &songstructure.phrases[phrase_idx]);
+            tout.phrase_beat = beat_num as f32 - songstructure.phrases[phrase_idx].beat as f32 + beat_fraction;
             if phrase_idx + 1 < songstructure.phrases.len() {
                 let next_phrase = &songstructure.phrases[phrase_idx + 1];
                 let next_phrase_in = next_phrase.beat as i32 - beat_num as i32;
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 
 use crate::log::ScopedLogger;
@@ -7,33 +7,47 @@ use crate::log::ScopedLogger;
 pub struct Config {
     entries: HashMap<String, String>,
     namespace: Option<String>,
+    // Namespace to look in for keys missing from `namespace`
+    fallback: Option<String>,
     pub logger: ScopedLogger,
 }
 
 impl Config {
     pub fn read(logger: ScopedLogger) -> Config {
-        let mut config = HashMap::new();
         if let Ok(src) = fs::read_to_string("config") {
-            let config_lines = src.lines();
-            for line in config_lines {
-                let line = line.trim();
-                if line.starts_with("#") || line.is_empty() {
-                    continue;
-                }
-                let splitindex = line.find(" ").unwrap_or(line.len());
-                let key = &line[..splitindex];
-                let value = &line[(splitindex + 1).min(line.len())..]; // Keys without values are simply blank
-                config.insert(key.to_string(), value.to_string());
-            }
-            if config.keys().len() == 0 {
-                logger.warn("Configuration is empty");
-            }
+            Config::parse(&src, logger)
         } else {
             logger.warn("Config file not found");
-        };
+            Config {
+                entries: HashMap::new(),
+                namespace: None,
+                fallback: None,
+                logger,
+            }
+        }
+    }
+
+    /// Parse config file contents
+    pub fn parse(src: &str, logger: ScopedLogger) -> Config {
+        let mut config = HashMap::new();
+        let config_lines = src.lines();
+        for line in config_lines {
+            let line = line.trim();
+            if line.starts_with("#") || line.is_empty() {
+                continue;
+            }
+            let splitindex = line.find(" ").unwrap_or(line.len());
+            let key = &line[..splitindex];
+            let value = &line[(splitindex + 1).min(line.len())..]; // Keys without values are simply blank
+            config.insert(key.to_string(), value.to_string());
+        }
+        if config.keys().len() == 0 {
+            logger.warn("Configuration is empty");
+        }
         Config {
             entries: config,
             namespace: None,
+            fallback: None,
             logger,
         }
     }
@@ -47,12 +61,20 @@ impl Config {
     }
 
     pub fn get<T: std::str::FromStr>(&self, key: &str) -> Option<T> {
+        let fallback_key = self.fallback.as_ref().map(|fallback| format!("{fallback}.{key}"));
         let key = if let Some(namespace) = &self.namespace {
             format!("{namespace}.{key}")
         } else {
             key.to_string()
         };
-        if let Some(val) = self.entries.get(&key) {
+        let (key, val) = match (self.entries.get(&key), fallback_key) {
+            (None, Some(fallback_key)) => {
+                let val = self.entries.get(&fallback_key);
+                (fallback_key, val)
+            }
+            (val, _) => (key, val),
+        };
+        if let Some(val) = val {
             if let Ok(val) = val.parse::<T>() {
                 Some(val)
             } else {
@@ -66,10 +88,51 @@ impl Config {
         }
     }
 
+    /// All entries whose key starts with `prefix`, with the prefix removed from the key. Sorted by key.
+    /// Entries of the fallback namespace are included unless the namespace has the same key.
+    pub fn entries_with_prefix(&self, prefix: &str) -> Vec<(String, String)> {
+        let full_prefix = |namespace: &Option<String>| match namespace {
+            Some(namespace) => format!("{namespace}.{prefix}"),
+            None => prefix.to_string(),
+        };
+        let mut entries = BTreeMap::new();
+        let mut namespaces = vec![full_prefix(&self.namespace)];
+        if self.fallback.is_some() {
+            namespaces.insert(0, full_prefix(&self.fallback));
+        }
+        // Fallback first, so the namespace's own entries replace it
+        for prefix in namespaces {
+            for (key, val) in &self.entries {
+                if let Some(key) = key.strip_prefix(&prefix) {
+                    entries.insert(key.to_string(), val.clone());
+                }
+            }
+        }
+        entries.into_iter().collect()
+    }
+
+    /// Namespace nested inside the current one, e.g. `sacn` to `sacn.deck0`
+    pub fn sub_namespace(&self, name: &str) -> Config {
+        let namespace = match &self.namespace {
+            Some(namespace) => format!("{namespace}.{name}"),
+            None => name.to_string(),
+        };
+        self.reduce_to_namespace(&namespace)
+    }
+
+    /// Like `sub_namespace`, but keys missing from it are read from the current namespace instead,
+    /// e.g. `osc.<target>.bundle` falls back to `osc.bundle`
+    pub fn sub_namespace_with_fallback(&self, name: &str) -> Config {
+        let mut config = self.sub_namespace(name);
+        config.fallback = self.namespace.clone();
+        config
+    }
+
     pub fn reduce_to_namespace(&self, namespace: &str) -> Config {
         Config {
             entries: self.entries.clone(),
             namespace: Some(namespace.to_string()),
+            fallback: None,
             logger: self.logger.clone(),
         }
     }
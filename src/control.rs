@@ -0,0 +1,242 @@
+// OSC control endpoint
+//
+// Listens for OSC messages on a UDP port and turns them into commands for the keeper. Receiving
+// happens on its own thread, the keeper picks up the commands between updates.
+
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+
+use rosc::{decoder::decode_udp, encoder::encode, OscMessage, OscPacket, OscTime, OscType};
+
+use crate::beatkeeper::TrackInfo;
+use crate::config::Config;
+use crate::events::{Event, Target};
+use crate::log::ScopedLogger;
+use crate::outputmodules::osc::split_bundle;
+
+pub enum Command {
+    /// Reply to the sender with the current state
+    Query,
+    /// Set the delay compensation in milliseconds
+    DelayCompensation(f32),
+    /// Realign outputs that keep their own phase, like Ableton Link, right away
+    Resync,
+    /// Use this deck as the master instead of the one Rekordbox reports, `None` to release
+    MasterDeckOverride(Option<usize>),
+}
+
+pub struct Control {
+    socket: UdpSocket,
+    rx: mpsc::Receiver<(SocketAddr, OscPacket)>,
+    logger: ScopedLogger,
+}
+
+impl Control {
+    pub fn new(conf: &Config, logger: ScopedLogger) -> Option<Control> {
+        let address = conf.get_or_default("address", "127.0.0.1:4461".to_string());
+        let socket = match UdpSocket::bind(&address) {
+            Ok(socket) => socket,
+            Err(e) => {
+                logger.err(&format!("Failed to listen for OSC commands on {address}: {e}"));
+                return None;
+            }
+        };
+        let receiver = match socket.try_clone() {
+            Ok(socket) => socket,
+            Err(e) => {
+                logger.err(&format!("Failed to open OSC control socket: {e}"));
+                return None;
+            }
+        };
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; rosc::decoder::MTU];
+            while let Ok((len, from)) = receiver.recv_from(&mut buf) {
+                // Malformed packets are dropped, there's no logger on this thread
+                let Ok((_, packet)) = decode_udp(&buf[..len]) else {
+                    continue;
+                };
+                if tx.send((from, packet)).is_err() {
+                    break; // Keeper is gone
+                }
+            }
+        });
+
+        logger.info(&format!("Listening for OSC commands on {address}"));
+        Some(Control { socket, rx, logger })
+    }
+
+    /// All commands received since the last call, with their sender
+    pub fn commands(&self) -> Vec<(SocketAddr, Command)> {
+        let mut messages = vec![];
+        for (from, packet) in self.rx.try_iter() {
+            flatten(packet, &mut |msg| messages.push((from, msg)));
+        }
+        messages
+            .into_iter()
+            .filter_map(|(from, msg)| match parse(&msg) {
+                Ok(command) => Some((from, command)),
+                Err(e) => {
+                    self.logger.warn(&format!("Ignoring {} from {from}: {e}", msg.addr));
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Send the state to `to` as one message per value under `/rkbx/state`, in as few bundles as fit in a packet
+    pub fn reply(&self, to: SocketAddr, delay_compensation: f32, master_override: Option<usize>, state: &[(Target, Event)]) {
+        let mut messages = vec![
+            ("/rkbx/state/delay_compensation".to_string(), vec![OscType::Float(delay_compensation)]),
+            (
+                "/rkbx/state/master_override".to_string(),
+                vec![OscType::Int(master_override.map(|d| d as i32).unwrap_or(-1))],
+            ),
+        ];
+        for (target, event) in state {
+            messages.extend(
+                state_messages(event)
+                    .into_iter()
+                    .map(|(name, args)| (format!("/rkbx/state/{target}/{name}"), args)),
+            );
+        }
+
+        let messages = messages
+            .into_iter()
+            .map(|(addr, args)| OscPacket::Message(OscMessage { addr, args }))
+            .collect();
+        // Timetag 1 means immediately
+        for bundle in split_bundle(OscTime { seconds: 0, fractional: 1 }, messages) {
+            let packet = match encode(&bundle) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    self.logger.err(&format!("Failed to encode OSC bundle: {e}"));
+                    continue;
+                }
+            };
+            if let Err(e) = self.socket.send_to(&packet, to) {
+                self.logger.err(&format!("Failed to reply to {to}: {e}"));
+                return;
+            }
+        }
+    }
+}
+
+fn flatten(packet: OscPacket, out: &mut impl FnMut(OscMessage)) {
+    match packet {
+        OscPacket::Message(msg) => out(msg),
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                flatten(packet, out);
+            }
+        }
+    }
+}
+
+fn number(arg: Option<&OscType>) -> Option<f32> {
+    match arg? {
+        OscType::Float(x) => Some(*x),
+        OscType::Double(x) => Some(*x as f32),
+        OscType::Int(x) => Some(*x as f32),
+        OscType::Long(x) => Some(*x as f32),
+        _ => None,
+    }
+}
+
+fn parse(msg: &OscMessage) -> Result<Command, String> {
+    match msg.addr.as_str() {
+        "/rkbx/query" => Ok(Command::Query),
+        "/rkbx/resync" => Ok(Command::Resync),
+        "/rkbx/delay_compensation" => number(msg.args.first())
+            .filter(|ms| ms.is_finite())
+            .map(Command::DelayCompensation)
+            .ok_or("expected a number of milliseconds".to_string()),
+        "/rkbx/deck/override" => match msg.args.first() {
+            // A negative deck or no argument releases the override
+            None => Ok(Command::MasterDeckOverride(None)),
+            arg => {
+                let deck = number(arg).ok_or("expected a deck index".to_string())?;
+                Ok(Command::MasterDeckOverride(if deck < 0. { None } else { Some(deck as usize) }))
+            }
+        },
+        _ => Err("unknown address".to_string()),
+    }
+}
+
+/// Address suffixes and arguments for the value carried by an event
+fn state_messages(event: &Event) -> Vec<(String, Vec<OscType>)> {
+    let float = |name: &str, value: f32| vec![(name.to_string(), vec![OscType::Float(value)])];
+    let int = |name: &str, value: i32| vec![(name.to_string(), vec![OscType::Int(value)])];
+    let string = |name: &str, value: &str| vec![(name.to_string(), vec![OscType::String(value.to_string())])];
+    match event {
+        Event::Bpm(bpm) => float("bpm", *bpm),
+        Event::OriginalBpm(bpm) => float("original_bpm", *bpm),
+        Event::Beat(beat) => float("beat", *beat),
+        Event::TrackBeat(beat) => float("track_beat", *beat),
+        Event::PhraseBeat(beat) => float("phrase_beat", *beat),
+        Event::BeatCount(count) => vec![(
+            "beat_count".to_string(),
+            vec![OscType::Int(count.beat), OscType::Int(count.bar), OscType::Int(count.beat_in_bar), OscType::Int(count.total_beats)],
+        )],
+        Event::Time(time) => float("time", *time),
+        Event::TrackLength(length) => float("track_length", *length),
+        Event::TimeRemaining(remaining) => float("time_remaining", *remaining),
+        Event::Progress(progress) => float("progress", *progress),
+        Event::Track(track) => TrackInfo::FIELDS
+            .iter()
+            .filter_map(|name| Some((format!("track/{name}"), vec![OscType::String(track.field(name)?)])))
+            .collect(),
+        Event::Phrase(phrase) => string("phrase", phrase),
+        Event::NextPhrase(phrase) => string("next_phrase", phrase),
+        Event::NextPhraseIn(beats) => int("next_phrase_in", *beats),
+        Event::NextCue(cue) => string("next_cue", &cue.as_ref().map(|c| c.name()).unwrap_or_default()),
+        Event::NextCueIn(beats) => int("next_cue_in", *beats),
+        Event::Energy(energy) => float("energy", *energy),
+        Event::Bands(bands) => vec![(
+            "bands".to_string(),
+            vec![OscType::Float(bands.low), OscType::Float(bands.mid), OscType::Float(bands.high)],
+        )],
+        Event::PlayState(state) => string("play_state", state.name()),
+        Event::MasterDeck(deck) => int("master_deck", *deck as i32),
+        // Not part of the state
+        Event::CuePassed(_) => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(addr: &str, args: Vec<OscType>) -> OscMessage {
+        OscMessage { addr: addr.to_string(), args }
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert!(matches!(parse(&message("/rkbx/query", vec![])), Ok(Command::Query)));
+        assert!(matches!(parse(&message("/rkbx/resync", vec![])), Ok(Command::Resync)));
+        assert!(matches!(
+            parse(&message("/rkbx/delay_compensation", vec![OscType::Int(25)])),
+            Ok(Command::DelayCompensation(ms)) if ms == 25.
+        ));
+        assert!(matches!(
+            parse(&message("/rkbx/deck/override", vec![OscType::Float(1.)])),
+            Ok(Command::MasterDeckOverride(Some(1)))
+        ));
+        assert!(matches!(
+            parse(&message("/rkbx/deck/override", vec![OscType::Int(-1)])),
+            Ok(Command::MasterDeckOverride(None))
+        ));
+        assert!(matches!(parse(&message("/rkbx/deck/override", vec![])), Ok(Command::MasterDeckOverride(None))));
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        assert!(parse(&message("/rkbx/unknown", vec![])).is_err());
+        assert!(parse(&message("/rkbx/delay_compensation", vec![])).is_err());
+        assert!(parse(&message("/rkbx/delay_compensation", vec![OscType::Float(f32::NAN)])).is_err());
+        assert!(parse(&message("/rkbx/deck/override", vec![OscType::String("1".to_string())])).is_err());
+    }
+}
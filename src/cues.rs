@@ -0,0 +1,51 @@
+use rekordcrate::anlz::{CueList, ExtendedCueList};
+
+/// A hot cue or memory cue from the ANLZ cue lists
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Cue {
+    /// Hot cue number, 1 for A, 2 for B and so on. 0 for memory cues
+    pub hot_cue: u8,
+    /// Position in seconds
+    pub time: f32,
+    /// Only available in the extended (EXT) cue lists
+    pub color: Option<(u8, u8, u8)>,
+    pub comment: String,
+}
+
+impl Cue {
+    /// Hot cue letter, or "Memory" for memory cues
+    pub fn name(&self) -> String {
+        if self.hot_cue == 0 {
+            "Memory".to_string()
+        } else {
+            ((b'A' + self.hot_cue - 1) as char).to_string()
+        }
+    }
+
+    /// Cues from a PCOB tag in the DAT file, no colours or comments
+    pub fn from_cue_list(list: &CueList) -> Vec<Cue> {
+        list.cues
+            .iter()
+            .map(|cue| Cue {
+                hot_cue: cue.hot_cue as u8,
+                time: cue.time as f32 / 1000.,
+                color: None,
+                comment: String::new(),
+            })
+            .collect()
+    }
+
+    /// Cues from a PCO2 tag in the EXT file
+    pub fn from_extended_cue_list(list: &ExtendedCueList) -> Vec<Cue> {
+        list.cues
+            .iter()
+            .map(|cue| Cue {
+                hot_cue: cue.hot_cue as u8,
+                time: cue.time as f32 / 1000.,
+                // Memory cues have no RGB colour, hot cues without a colour are all zero
+                color: Some(cue.hot_cue_color_rgb).filter(|rgb| *rgb != (0, 0, 0)),
+                comment: cue.comment.to_string().trim_end_matches('\0').to_string(),
+            })
+            .collect()
+    }
+}
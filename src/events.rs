@@ -0,0 +1,134 @@
+use std::fmt;
+use std::sync::mpsc;
+
+use crate::beatkeeper::TrackInfo;
+use crate::cues::Cue;
+use crate::outputmodules::OutputModule;
+
+/// Which deck an event refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Deck(usize),
+    Master,
+}
+
+impl fmt::Display for Target {
+    /// Formats as used in output addresses, `master` or the deck index
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Deck(deck) => write!(f, "{deck}"),
+            Target::Master => write!(f, "master"),
+        }
+    }
+}
+
+/// Transport state derived from how fast the sample position moves compared to the tempo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayState {
+    Paused,
+    Playing,
+    Scratching,
+    Reverse,
+}
+
+impl PlayState {
+    /// Numeric value for outputs: 0 paused, 1 playing, 2 scratching, 3 reverse
+    pub fn to_index(self) -> u8 {
+        match self {
+            PlayState::Paused => 0,
+            PlayState::Playing => 1,
+            PlayState::Scratching => 2,
+            PlayState::Reverse => 3,
+        }
+    }
+
+    /// Only normal playback counts as playing, scratching and reverse play hold the transport
+    pub fn is_playing(self) -> bool {
+        self == PlayState::Playing
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PlayState::Paused => "paused",
+            PlayState::Playing => "playing",
+            PlayState::Scratching => "scratching",
+            PlayState::Reverse => "reverse",
+        }
+    }
+}
+
+/// Whole beat and bar counters from the beatgrid, all 0 when no beatgrid is loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BeatCount {
+    /// Absolute beat number in the track, starting at 1
+    pub beat: i32,
+    /// Bar number in the track, starting at 1 on the first downbeat
+    pub bar: i32,
+    /// Beat within the bar, 1..=4
+    pub beat_in_bar: i32,
+    /// Number of beats in the beatgrid
+    pub total_beats: i32,
+}
+
+/// Waveform colour at the playhead, each 0..1
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Bands {
+    pub low: f32,
+    pub mid: f32,
+    pub high: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Bpm(f32),
+    OriginalBpm(f32),
+    /// Position in the bar, 0..4
+    Beat(f32),
+    /// Beats since the first beat of the beatgrid
+    TrackBeat(f32),
+    /// Beats since the start of the current phrase
+    PhraseBeat(f32),
+    BeatCount(BeatCount),
+    Time(f32),
+    /// Track duration in seconds, 0 if unknown
+    TrackLength(f32),
+    /// Seconds left until the end of the track
+    TimeRemaining(f32),
+    /// Position in the track, 0..1
+    Progress(f32),
+    Track(TrackInfo),
+    Phrase(String),
+    NextPhrase(String),
+    NextPhraseIn(i32),
+    /// The next cue ahead of the playhead, if any
+    NextCue(Option<Cue>),
+    /// Beats until the next cue
+    NextCueIn(i32),
+    /// The playhead just passed a cue
+    CuePassed(Cue),
+    /// Waveform amplitude at the playhead, 0..1
+    Energy(f32),
+    /// All 0 for tracks without a colour waveform
+    Bands(Bands),
+    PlayState(PlayState),
+    /// Sent with `Target::Master` when the master deck changes
+    MasterDeck(usize),
+}
+
+/// Forwards every event to a channel, for embedding the keeper in other programs
+pub(crate) struct EventSender {
+    tx: mpsc::Sender<(Target, Event)>,
+}
+
+impl EventSender {
+    pub fn new(tx: mpsc::Sender<(Target, Event)>) -> Self {
+        Self { tx }
+    }
+}
+
+impl OutputModule for EventSender {
+    fn on_event(&mut self, target: Target, event: &Event) {
+        // The receiver going away is not an error, the keeper keeps running for the other modules
+        let _ = self.tx.send((target, event.clone()));
+    }
+}
@@ -0,0 +1,22 @@
+//! Reads live timing, phrase and track info from Rekordbox.
+//!
+//! Run a [`BeatKeeper`] on its own thread with [`BeatKeeper::spawn`], or set one up with
+//! [`BeatKeeper::new`] and drive it with [`BeatKeeper::poll`], then consume its [`Event`]s.
+
+pub mod artwork;
+pub mod beatkeeper;
+pub mod config;
+pub mod control;
+pub mod cues;
+pub mod events;
+pub mod log;
+pub mod memory;
+pub mod metadata;
+pub mod offsets;
+pub mod outputmodules;
+mod recorder;
+pub mod utils;
+
+pub use beatkeeper::{BeatKeeper, TrackInfo};
+pub use events::{Event, Target};
+pub use offsets::RekordboxOffsets;
@@ -47,6 +47,11 @@ impl Logger {
         }
     }
 
+    /// Empty line to separate sections of the output
+    pub fn blank(&self) {
+        let _ = writeln!(&mut self.stdout.borrow_mut());
+    }
+
     pub fn debug(&self, source: &str, message: &str) {
         self.log(source, message, LogLevel::Debug);
     }
@@ -82,6 +87,10 @@ impl ScopedLogger {
         }
     }
 
+    pub fn blank(&self) {
+        self.logger.blank();
+    }
+
     pub fn debug(&self, message: &str) {
         self.logger.debug(&self.source, message);
     }
@@ -1,20 +1,11 @@
-use beatkeeper::BeatKeeper;
-use log::{Logger, ScopedLogger};
-use outputmodules::ModuleDefinition;
+use rkbx_link::beatkeeper::BeatKeeper;
+use rkbx_link::config;
+use rkbx_link::log::{Logger, ScopedLogger};
+use rkbx_link::offsets::RekordboxOffsets;
+use rkbx_link::outputmodules::{self, ModuleDefinition};
 use std::path::Path;
 use std::{fs, rc::Rc};
 
-mod offsets;
-use offsets::RekordboxOffsets;
-
-mod outputmodules;
-
-mod beatkeeper;
-mod config;
-mod log;
-mod utils;
-mod memory;
-
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[cfg(feature = "dev")]
@@ -31,6 +22,9 @@ const REPO: &str = "grufkork/rkbx_link/refs/heads/master";
 const OFFSETS_PATH: &str = "./data/offsets";
 #[cfg(target_os = "macos")]
 const OFFSETS_PATH: &str = "./data/offsets-macos";
+// Rekordbox runs as the Windows build under Wine, so the Windows offsets apply
+#[cfg(target_os = "linux")]
+const OFFSETS_PATH: &str = "./data/offsets";
 
 fn main() {
     println!();
@@ -72,8 +66,21 @@ fn main() {
             outputmodules::abletonlink::AbletonLink::create,
         ),
         ModuleDefinition::new("osc", "OSC", outputmodules::osc::Osc::create),
+        ModuleDefinition::new("resolume", "Resolume", outputmodules::resolume::Resolume::create),
 		ModuleDefinition::new("sacn", "sACN", outputmodules::sacn::Sacn::create),
+        ModuleDefinition::new("artnet", "Art-Net", outputmodules::artnet::ArtNet::create),
+        ModuleDefinition::new("midi", "MIDI", outputmodules::midi::Midi::create),
+        ModuleDefinition::new(
+            "websocket",
+            "WebSocket",
+            outputmodules::websocket::WebSocket::create,
+        ),
         ModuleDefinition::new("file", "File", outputmodules::file::File::create),
+        ModuleDefinition::new(
+            "artwork",
+            "Artwork",
+            outputmodules::artwork::ArtworkOutput::create,
+        ),
         ModuleDefinition::new(
             "setlist",
             "Setlist",
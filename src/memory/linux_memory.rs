@@ -0,0 +1,190 @@
+// Linux process memory reading for Rekordbox running under Wine/Proton
+
+use std::fs::{self, File};
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use sysinfo::{ProcessesToUpdate, System};
+
+use crate::memory::{MemBackend, MemoryReadError, MemoryReadErrorType};
+
+const PROCESS_NAME: &str = "rekordbox.exe";
+
+pub struct LinuxMem {
+    mem: File,
+    base: usize,
+    prefix: PathBuf,
+}
+
+impl LinuxMem {
+    pub fn new() -> Result<Self, MemoryReadError> {
+        let candidates = LinuxMem::find_pids();
+        if candidates.is_empty() {
+            return Err(LinuxMem::error(MemoryReadErrorType::ProcessNotFound, None));
+        }
+
+        // Wine may spawn several processes carrying the exe name (preloader, server helpers),
+        // pick the first one that actually has the module mapped
+        let Some((pid, base)) = candidates
+            .into_iter()
+            .find_map(|pid| LinuxMem::find_module_base(pid).map(|base| (pid, base)))
+        else {
+            return Err(LinuxMem::error(MemoryReadErrorType::ModuleNotFound, Some(PROCESS_NAME.to_string())));
+        };
+
+        let mem = File::open(format!("/proc/{pid}/mem")).map_err(|e| {
+            LinuxMem::error(
+                MemoryReadErrorType::SnapshotFailed,
+                Some(format!("Could not open /proc/{pid}/mem: {e}. Run as the same user as Wine and check /proc/sys/kernel/yama/ptrace_scope")),
+            )
+        })?;
+
+        Ok(LinuxMem { mem, base, prefix: LinuxMem::find_wine_prefix(pid) })
+    }
+
+    /// The Wine prefix of the process, from its environment. Without `WINEPREFIX` Wine uses `~/.wine`.
+    fn find_wine_prefix(pid: u32) -> PathBuf {
+        let environ = fs::read(format!("/proc/{pid}/environ")).unwrap_or_default();
+        let var = |name: &str| {
+            environ
+                .split(|b| *b == 0)
+                .find_map(|entry| entry.strip_prefix(format!("{name}=").as_bytes()))
+                .map(|value| String::from_utf8_lossy(value).into_owned())
+        };
+        if let Some(prefix) = var("WINEPREFIX").filter(|p| !p.is_empty()) {
+            return PathBuf::from(prefix);
+        }
+        let home = var("HOME").or_else(|| std::env::var("HOME").ok()).unwrap_or_default();
+        Path::new(&home).join(".wine")
+    }
+
+    /// Find all processes that look like the Wine-hosted Rekordbox executable.
+    /// Wine sets the process name to the exe name, but the command line is checked as well
+    /// since some launchers (Proton, Bottles) leave the loader as the process name.
+    fn find_pids() -> Vec<u32> {
+        let mut sys = System::new();
+        sys.refresh_processes(ProcessesToUpdate::All, true);
+
+        sys.processes()
+            .values()
+            .filter(|p| {
+                let name_matches = p
+                    .name()
+                    .to_str()
+                    .map(|s| s.eq_ignore_ascii_case(PROCESS_NAME))
+                    .unwrap_or(false);
+                let cmd_matches = p
+                    .cmd()
+                    .first()
+                    .and_then(|s| s.to_str())
+                    .map(|s| s.replace('\\', "/").to_lowercase().ends_with(&format!("/{PROCESS_NAME}")))
+                    .unwrap_or(false);
+                name_matches || cmd_matches
+            })
+            .map(|p| p.pid().as_u32())
+            .collect()
+    }
+
+    /// Get the load address of rekordbox.exe from /proc/<pid>/maps.
+    /// Wine maps PE images straight from the file, so the first mapping of the exe at
+    /// file offset 0 is the image base the Windows offsets are relative to.
+    fn find_module_base(pid: u32) -> Option<usize> {
+        let maps = fs::read_to_string(format!("/proc/{pid}/maps")).ok()?;
+        for line in maps.lines() {
+            // start-end perms offset dev inode path
+            let mut fields = line.split_whitespace();
+            let (Some(range), Some(_perms), Some(offset), Some(_dev), Some(_inode)) =
+                (fields.next(), fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let path = fields.collect::<Vec<&str>>().join(" ");
+            let filename = path.rsplit('/').next().unwrap_or("");
+            if !filename.eq_ignore_ascii_case(PROCESS_NAME) {
+                continue;
+            }
+            if usize::from_str_radix(offset, 16).ok() != Some(0) {
+                continue;
+            }
+            let start = range.split('-').next()?;
+            return usize::from_str_radix(start, 16).ok();
+        }
+        None
+    }
+
+    fn error(error_type: MemoryReadErrorType, detail: Option<String>) -> MemoryReadError {
+        MemoryReadError {
+            pointer: None,
+            address: 0,
+            detail,
+            error_type,
+        }
+    }
+}
+
+impl MemBackend for LinuxMem {
+    fn read<T>(&self, address: usize) -> Result<T, MemoryReadError> {
+        let mut value: T = unsafe { mem::zeroed() };
+        let buf = unsafe {
+            std::slice::from_raw_parts_mut(&mut value as *mut T as *mut u8, mem::size_of::<T>())
+        };
+
+        if let Err(e) = self.mem.read_exact_at(buf, address as u64) {
+            return Err(MemoryReadError {
+                pointer: None,
+                address,
+                detail: Some(e.to_string()),
+                error_type: MemoryReadErrorType::ReadMemoryFailed,
+            });
+        }
+
+        Ok(value)
+    }
+
+    fn get_base_offset(&self) -> usize {
+        self.base
+    }
+
+    fn translate_path(&self, path: String) -> String {
+        wine_to_unix_path(&path, &self.prefix).unwrap_or(path)
+    }
+}
+
+/// Map a Windows path inside Wine to the host path, through the drive symlinks in `<prefix>/dosdevices`.
+/// Returns `None` for paths without a drive letter.
+fn wine_to_unix_path(path: &str, prefix: &Path) -> Option<String> {
+    let path = path.replace('\\', "/");
+    let (drive, rest) = path.split_once(':')?;
+    if drive.len() != 1 || !drive.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let drive = prefix.join("dosdevices").join(format!("{}:", drive.to_ascii_lowercase()));
+    Some(format!("{}/{}", drive.to_string_lossy(), rest.trim_start_matches('/')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_drive_letters_through_dosdevices() {
+        let prefix = Path::new("/home/dj/.wine");
+        assert_eq!(
+            wine_to_unix_path("C:\\Users\\dj\\AppData\\Roaming\\Pioneer\\rekordbox\\share\\PIONEER\\USBANLZ\\ANLZ0000.DAT", prefix).as_deref(),
+            Some("/home/dj/.wine/dosdevices/c:/Users/dj/AppData/Roaming/Pioneer/rekordbox/share/PIONEER/USBANLZ/ANLZ0000.DAT")
+        );
+        assert_eq!(
+            wine_to_unix_path("E:/PIONEER/USBANLZ/P016/0000875E/ANLZ0000.DAT", prefix).as_deref(),
+            Some("/home/dj/.wine/dosdevices/e:/PIONEER/USBANLZ/P016/0000875E/ANLZ0000.DAT")
+        );
+    }
+
+    #[test]
+    fn keeps_paths_without_drive_letter() {
+        let prefix = Path::new("/home/dj/.wine");
+        assert_eq!(wine_to_unix_path("", prefix), None);
+        assert_eq!(wine_to_unix_path("/mnt/usb/PIONEER/ANLZ0000.DAT", prefix), None);
+        assert_eq!(wine_to_unix_path("//?/C:/x", prefix), None);
+    }
+}
@@ -2,6 +2,7 @@ use std::marker::PhantomData;
 use core::fmt;
 
 use crate::log::ScopedLogger;
+use crate::offsets::RekordboxOffsets;
 
 #[cfg(target_os = "windows")]
 pub mod windows_memory;
@@ -13,20 +14,72 @@ pub mod macos_memory;
 #[cfg(target_os = "macos")]
 pub type ActiveBackend = macos_memory::MacMemory;
 
+#[cfg(target_os = "linux")]
+pub mod linux_memory;
+#[cfg(target_os = "linux")]
+pub type ActiveBackend = linux_memory::LinuxMem;
+
+pub mod replay_memory;
+
 pub trait MemBackend{
     // fn new() -> Result<Box<dyn MemBackend>, String>;
     fn get_base_offset(&self) -> usize;
     fn read<T>(&self, address: usize) -> Result<T, MemoryReadError>;
+
+    /// Turn a file path read from memory into one this process can open
+    fn translate_path(&self, path: String) -> String {
+        path
+    }
+}
+
+/// Where memory reads are served from
+#[derive(Clone)]
+pub enum MemSource {
+    Rekordbox,
+    Replay { path: String, looping: bool },
+}
+
+enum Backend {
+    Process(ActiveBackend),
+    Replay(replay_memory::ReplayMem),
+}
+
+impl MemBackend for Backend {
+    fn get_base_offset(&self) -> usize {
+        match self {
+            Backend::Process(b) => b.get_base_offset(),
+            Backend::Replay(b) => b.get_base_offset(),
+        }
+    }
+
+    fn read<T>(&self, address: usize) -> Result<T, MemoryReadError> {
+        match self {
+            Backend::Process(b) => b.read::<T>(address),
+            Backend::Replay(b) => b.read::<T>(address),
+        }
+    }
+
+    fn translate_path(&self, path: String) -> String {
+        match self {
+            Backend::Process(b) => b.translate_path(path),
+            Backend::Replay(b) => b.translate_path(path),
+        }
+    }
 }
 
 pub struct MemReader{
-    backend: ActiveBackend,
+    backend: Backend,
     base: usize,
 }
 
 impl MemReader{
-    pub fn new() -> Result<Self, MemoryReadError>{
-        let backend = ActiveBackend::new()?;
+    pub fn new(source: &MemSource, offsets: &RekordboxOffsets, decks: usize) -> Result<Self, MemoryReadError>{
+        let backend = match source {
+            MemSource::Rekordbox => Backend::Process(ActiveBackend::new()?),
+            MemSource::Replay { path, looping } => {
+                Backend::Replay(replay_memory::ReplayMem::new(path, *looping, offsets, decks)?)
+            }
+        };
         Ok(MemReader { base: backend.get_base_offset(), backend })
     }
 
@@ -54,6 +107,10 @@ impl MemReader{
         self.backend.read::<T>(address)
     }
 
+    pub fn translate_path(&self, path: String) -> String {
+        self.backend.translate_path(path)
+    }
+
 }
 
 
@@ -118,6 +175,7 @@ pub enum MemoryReadErrorType{
     ReadMemoryFailed,
     WriteMemoryFailed,
     ModuleNotFound,
+    ReplayFileInvalid,
 }
 
 #[derive(PartialEq, Clone)]
@@ -0,0 +1,268 @@
+// File-backed memory backend serving a recorded or synthetic timeline instead of a live Rekordbox process
+//
+// Timeline format, one keyframe per line:
+//   <time ms> master <deck index>
+//   <time ms> bpm <deck> <current bpm>
+//   <time ms> position <deck> <sample position>
+//   <time ms> track <deck> <track info text, newlines escaped as \n>
+//   <time ms> anlz <deck> <path to ANLZ .DAT file>
+// Empty lines and lines starting with # are ignored. Sample positions are interpolated linearly
+// between keyframes of the same deck, all other values hold until the next keyframe.
+
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::time::Instant;
+
+use crate::memory::{MemBackend, MemoryReadError, MemoryReadErrorType, Pointer};
+use crate::offsets::RekordboxOffsets;
+
+// Fake address space. The base mimics a PE image base, regions are handed out for every
+// pointer hop so the offset pointer chains resolve like they would in the real process.
+const BASE_ADDRESS: usize = 0x0040_0000;
+const REGION_START: usize = 0x1000_0000;
+const REGION_SIZE: usize = 0x0100_0000;
+
+#[derive(Clone, Copy)]
+enum Field {
+    Masterdeck,
+    Bpm(usize),
+    SamplePosition(usize),
+    TrackInfo(usize),
+    AnlzPath(usize),
+}
+
+#[derive(Default)]
+struct DeckTimeline {
+    bpm: Vec<(f64, f32)>,
+    position: Vec<(f64, i64)>,
+    track_info: Vec<(f64, String)>,
+    anlz_path: Vec<(f64, String)>,
+}
+
+pub struct Timeline {
+    masterdeck: Vec<(f64, u8)>,
+    decks: Vec<DeckTimeline>,
+    length: f64,
+}
+
+impl Timeline {
+    pub fn parse(src: &str) -> Result<Timeline, String> {
+        let mut timeline = Timeline {
+            masterdeck: vec![],
+            decks: vec![],
+            length: 0.,
+        };
+
+        for (n, line) in src.lines().enumerate() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |msg: &str| format!("Line {}: {msg}", n + 1);
+
+            let mut parts = line.splitn(3, ' ');
+            let time = parts
+                .next()
+                .and_then(|x| x.parse::<f64>().ok())
+                .ok_or_else(|| err("Invalid timestamp"))?;
+            let field = parts.next().ok_or_else(|| err("Missing field"))?;
+            let rest = parts.next().unwrap_or("");
+            timeline.length = timeline.length.max(time);
+
+            if field == "master" {
+                let deck = rest.trim().parse::<u8>().map_err(|_| err("Invalid master deck index"))?;
+                timeline.masterdeck.push((time, deck));
+                continue;
+            }
+
+            let (deck, value) = rest.split_once(' ').unwrap_or((rest, ""));
+            let deck = deck.parse::<usize>().map_err(|_| err("Invalid deck index"))?;
+            if timeline.decks.len() <= deck {
+                timeline.decks.resize_with(deck + 1, Default::default);
+            }
+            let deck = &mut timeline.decks[deck];
+
+            match field {
+                "bpm" => deck.bpm.push((time, value.trim().parse().map_err(|_| err("Invalid BPM"))?)),
+                "position" => deck.position.push((time, value.trim().parse().map_err(|_| err("Invalid sample position"))?)),
+                "track" => deck.track_info.push((time, unescape_value(value))),
+                "anlz" => deck.anlz_path.push((time, value.to_string())),
+                _ => return Err(err(&format!("Unknown field '{field}'"))),
+            }
+        }
+
+        Ok(timeline)
+    }
+
+    fn hold<T: Clone>(keyframes: &[(f64, T)], time: f64) -> Option<T> {
+        keyframes.iter().take_while(|(t, _)| *t <= time).last().map(|(_, v)| v.clone())
+    }
+
+    fn interpolate(keyframes: &[(f64, i64)], time: f64) -> i64 {
+        let next = keyframes.iter().position(|(t, _)| *t > time);
+        match next {
+            None => keyframes.last().map(|(_, v)| *v).unwrap_or(0),
+            Some(0) => keyframes[0].1,
+            Some(i) => {
+                let (t0, v0) = keyframes[i - 1];
+                let (t1, v1) = keyframes[i];
+                v0 + ((v1 - v0) as f64 * (time - t0) / (t1 - t0)) as i64
+            }
+        }
+    }
+}
+
+/// Escape a value so it fits on a single timeline line
+pub fn escape_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some(c) => out.push(c),
+                None => out.push('\\'),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+pub struct ReplayMem {
+    words: HashMap<usize, usize>,
+    fields: HashMap<usize, Field>,
+    timeline: Timeline,
+    start: Instant,
+    looping: bool,
+}
+
+impl ReplayMem {
+    pub fn new(path: &str, looping: bool, offsets: &RekordboxOffsets, decks: usize) -> Result<Self, MemoryReadError> {
+        let error = |detail: String| MemoryReadError {
+            pointer: None,
+            address: 0,
+            detail: Some(detail),
+            error_type: MemoryReadErrorType::ReplayFileInvalid,
+        };
+        let src = fs::read_to_string(path).map_err(|e| error(format!("Could not read {path}: {e}")))?;
+        let timeline = Timeline::parse(&src).map_err(|e| error(format!("{path}: {e}")))?;
+
+        let mut mem = ReplayMem {
+            words: HashMap::new(),
+            fields: HashMap::new(),
+            timeline,
+            start: Instant::now(),
+            looping,
+        };
+
+        let mut next_region = REGION_START;
+        mem.place(&offsets.masterdeck_index, Field::Masterdeck, &mut next_region);
+        for deck in 0..decks {
+            if let Some(p) = offsets.current_bpm.get(deck) {
+                mem.place(p, Field::Bpm(deck), &mut next_region);
+            }
+            if let Some(p) = offsets.sample_position.get(deck) {
+                mem.place(p, Field::SamplePosition(deck), &mut next_region);
+            }
+            if let Some(p) = offsets.track_info.get(deck) {
+                mem.place(p, Field::TrackInfo(deck), &mut next_region);
+            }
+            if let Some(p) = offsets.anlz_path.get(deck) {
+                mem.place(p, Field::AnlzPath(deck), &mut next_region);
+            }
+        }
+
+        Ok(mem)
+    }
+
+    /// Lay out the pointer words needed for `pointer` to resolve to the slot of `field`.
+    /// Chains sharing a prefix share regions, just like in the real process.
+    fn place(&mut self, pointer: &Pointer, field: Field, next_region: &mut usize) {
+        let mut address = BASE_ADDRESS;
+        for offset in &pointer.offsets {
+            address = *self.words.entry(address + offset).or_insert_with(|| {
+                let region = *next_region;
+                *next_region += REGION_SIZE;
+                region
+            });
+        }
+        self.fields.insert(address + pointer.final_offset, field);
+    }
+
+    fn current_time(&self) -> f64 {
+        let time = self.start.elapsed().as_secs_f64() * 1000.;
+        if self.looping && self.timeline.length > 0. {
+            time % self.timeline.length
+        } else {
+            time
+        }
+    }
+
+    fn field_bytes(&self, field: Field) -> Vec<u8> {
+        let time = self.current_time();
+        let deck = |i: usize| self.timeline.decks.get(i);
+        let text = |keyframes: Option<&Vec<(f64, String)>>| {
+            keyframes
+                .and_then(|k| Timeline::hold(k, time))
+                .unwrap_or_default()
+                .into_bytes()
+        };
+
+        match field {
+            Field::Masterdeck => vec![Timeline::hold(&self.timeline.masterdeck, time).unwrap_or(0)],
+            Field::Bpm(i) => deck(i)
+                .and_then(|d| Timeline::hold(&d.bpm, time))
+                .unwrap_or(0.)
+                .to_ne_bytes()
+                .to_vec(),
+            Field::SamplePosition(i) => deck(i)
+                .map(|d| Timeline::interpolate(&d.position, time))
+                .unwrap_or(0)
+                .to_ne_bytes()
+                .to_vec(),
+            Field::TrackInfo(i) => text(deck(i).map(|d| &d.track_info)),
+            Field::AnlzPath(i) => text(deck(i).map(|d| &d.anlz_path)),
+        }
+    }
+}
+
+impl MemBackend for ReplayMem {
+    fn read<T>(&self, address: usize) -> Result<T, MemoryReadError> {
+        let mut bytes = if let Some(field) = self.fields.get(&address) {
+            self.field_bytes(*field)
+        } else if let Some(word) = self.words.get(&address) {
+            word.to_ne_bytes().to_vec()
+        } else {
+            return Err(MemoryReadError {
+                pointer: None,
+                address,
+                detail: Some("Address not part of the replay layout".to_string()),
+                error_type: MemoryReadErrorType::ReadMemoryFailed,
+            });
+        };
+
+        // Strings are zero-padded, and always leave room for a terminator
+        let size = mem::size_of::<T>();
+        if bytes.len() >= size && size > 8 {
+            bytes.truncate(size - 1);
+        }
+        bytes.resize(size, 0);
+
+        let mut value: T = unsafe { mem::zeroed() };
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), &mut value as *mut T as *mut u8, size);
+        }
+        Ok(value)
+    }
+
+    fn get_base_offset(&self) -> usize {
+        BASE_ADDRESS
+    }
+}
@@ -0,0 +1,226 @@
+// Track metadata from the export.pdb database of Rekordbox USB exports
+//
+// Tracks are matched through the ANLZ path Rekordbox has loaded, which for exported tracks
+// points into PIONEER/USBANLZ on the drive and is stored as the analyze path of the track row.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::SystemTime;
+
+use binrw::BinRead;
+use rekordcrate::pdb::{Header, Row};
+use rekordcrate::util::DeviceSQLString;
+
+use crate::beatkeeper::TrackInfo;
+use crate::log::ScopedLogger;
+
+const ANLZ_DIR: &str = "/PIONEER/USBANLZ/";
+const PDB_PATH: &str = "PIONEER/rekordbox/export.pdb";
+
+type ReadResult = Result<HashMap<String, TrackInfo>, String>;
+
+enum Tracks {
+    // Parsing on a background thread, large exports take a while
+    Loading(mpsc::Receiver<ReadResult>),
+    // Keyed by analyze path, relative to the export root
+    Loaded(HashMap<String, TrackInfo>),
+    // Not retried until the file changes
+    Failed,
+}
+
+struct Export {
+    modified: Option<SystemTime>,
+    tracks: Tracks,
+}
+
+pub struct PdbResolver {
+    exports: HashMap<PathBuf, Export>,
+    logger: ScopedLogger,
+}
+
+impl PdbResolver {
+    pub fn new(logger: ScopedLogger) -> Self {
+        Self {
+            exports: HashMap::new(),
+            logger,
+        }
+    }
+
+    /// Fill in `track` from the export the ANLZ file belongs to. Values read from memory are kept,
+    /// only empty fields are filled, except BPM, duration and artwork which only exist in the export.
+    /// Returns false if the path is not part of a USB export, the track was not found or the export
+    /// is still being read, in which case a later call fills it in.
+    pub fn enrich(&mut self, anlz_path: &str, track: &mut TrackInfo) -> bool {
+        let anlz_path = anlz_path.replace('\\', "/");
+        let Some(split) = anlz_path.find(ANLZ_DIR) else {
+            return false;
+        };
+        let mut root = anlz_path[..split].to_string();
+        // A bare drive letter like E: is relative to the current directory on that drive
+        if root.ends_with(':') {
+            root.push('/');
+        }
+        let root = PathBuf::from(root);
+        let relative = &anlz_path[split..];
+
+        let Some(tracks) = self.load(&root) else {
+            return false;
+        };
+        let Some(found) = tracks.get(relative) else {
+            self.logger.debug(&format!("No track in export.pdb with ANLZ path {relative}"));
+            return false;
+        };
+
+        for (value, from_pdb) in [
+            (&mut track.title, &found.title),
+            (&mut track.artist, &found.artist),
+            (&mut track.album, &found.album),
+            (&mut track.key, &found.key),
+            (&mut track.genre, &found.genre),
+            (&mut track.label, &found.label),
+            (&mut track.comment, &found.comment),
+        ] {
+            if value.is_empty() {
+                *value = from_pdb.clone();
+            }
+        }
+        if track.rating == 0 {
+            track.rating = found.rating;
+        }
+        track.bpm = found.bpm;
+        track.duration = found.duration;
+        track.artwork = found.artwork.clone();
+        true
+    }
+
+    /// Get the tracks of the export at `root`. Starts reading it in the background if it is new
+    /// or has changed on disk, returning `None` until it is done or if reading failed.
+    fn load(&mut self, root: &Path) -> Option<&HashMap<String, TrackInfo>> {
+        let pdb_path = root.join(PDB_PATH);
+        let modified = fs::metadata(&pdb_path).and_then(|m| m.modified()).ok();
+
+        let up_to_date = self
+            .exports
+            .get(root)
+            .map(|e| e.modified == modified)
+            .unwrap_or(false);
+        if !up_to_date {
+            self.logger.info(&format!("Reading {}", pdb_path.display()));
+            let (tx, rx) = mpsc::channel();
+            let (path, thread_root) = (pdb_path.clone(), root.to_path_buf());
+            thread::spawn(move || {
+                let _ = tx.send(read_export(&path, &thread_root));
+            });
+            self.exports.insert(root.to_path_buf(), Export { modified, tracks: Tracks::Loading(rx) });
+        }
+
+        let export = self.exports.get_mut(root)?;
+        if let Tracks::Loading(rx) = &export.tracks {
+            let result = match rx.try_recv() {
+                Ok(result) => result,
+                Err(mpsc::TryRecvError::Empty) => return None,
+                Err(mpsc::TryRecvError::Disconnected) => Err("reader thread stopped".to_string()),
+            };
+            export.tracks = match result {
+                Ok(tracks) => {
+                    self.logger.info(&format!("Loaded {} tracks from {}", tracks.len(), pdb_path.display()));
+                    Tracks::Loaded(tracks)
+                }
+                Err(e) => {
+                    self.logger.err(&format!("Failed to read {}: {e}", pdb_path.display()));
+                    Tracks::Failed
+                }
+            };
+        }
+        match &export.tracks {
+            Tracks::Loaded(tracks) => Some(tracks),
+            _ => None,
+        }
+    }
+}
+
+fn text(s: &DeviceSQLString) -> String {
+    s.clone().into_string().unwrap_or_default()
+}
+
+fn read_export(pdb_path: &Path, root: &Path) -> ReadResult {
+    let file = File::open(pdb_path).map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(file);
+    let header = Header::read(&mut reader).map_err(|e| e.to_string())?;
+
+    let mut rows = vec![];
+    for table in &header.tables {
+        let pages = header
+            .read_pages(&mut reader, binrw::Endian::NATIVE, (&table.first_page, &table.last_page))
+            .map_err(|e| e.to_string())?;
+        for page in pages {
+            for row_group in page.row_groups.iter() {
+                rows.extend(row_group.present_rows().cloned());
+            }
+        }
+    }
+
+    // Names are stored in their own tables and referenced by id from the track rows
+    let mut artists = HashMap::new();
+    let mut albums = HashMap::new();
+    let mut keys = HashMap::new();
+    let mut genres = HashMap::new();
+    let mut labels = HashMap::new();
+    let mut artworks = HashMap::new();
+    for row in &rows {
+        match row {
+            Row::Artist(artist) => {
+                artists.insert(artist.id.0, text(&artist.name));
+            }
+            Row::Album(album) => {
+                albums.insert(album.id.0, text(&album.name));
+            }
+            Row::Key(key) => {
+                keys.insert(key.id.0, text(&key.name));
+            }
+            Row::Genre(genre) => {
+                genres.insert(genre.id.0, text(&genre.name));
+            }
+            Row::Label(label) => {
+                labels.insert(label.id.0, text(&label.name));
+            }
+            Row::Artwork(artwork) => {
+                // Paths are relative to the export root
+                let path = text(&artwork.path);
+                artworks.insert(artwork.id.0, root.join(path.trim_start_matches('/')).to_string_lossy().to_string());
+            }
+            _ => (),
+        }
+    }
+
+    let lookup = |map: &HashMap<u32, String>, id: u32| map.get(&id).cloned().unwrap_or_default();
+    let mut tracks = HashMap::new();
+    for row in &rows {
+        let Row::Track(track) = row else {
+            continue;
+        };
+        tracks.insert(
+            text(&track.analyze_path).replace('\\', "/"),
+            TrackInfo {
+                title: text(&track.title),
+                artist: lookup(&artists, track.artist_id.0),
+                album: lookup(&albums, track.album_id.0),
+                key: lookup(&keys, track.key_id.0),
+                genre: lookup(&genres, track.genre_id.0),
+                label: lookup(&labels, track.label_id.0),
+                rating: track.rating.min(5),
+                comment: text(&track.comment),
+                bpm: track.tempo as f32 / 100.,
+                duration: track.duration as f32,
+                artwork: lookup(&artworks, track.artwork_id.0),
+                // Set from the ANLZ file by the keeper
+                file_path: String::new(),
+            },
+        );
+    }
+    Ok(tracks)
+}
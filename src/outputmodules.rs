@@ -1,18 +1,41 @@
 use crate::beatkeeper::TrackInfo;
 use crate::config::Config;
+use crate::events::{Event, Target};
 use crate::log::ScopedLogger;
 
 pub mod abletonlink;
+pub mod artnet;
+pub mod artwork;
 pub mod display;
+pub mod dmx;
 pub mod file;
+mod http;
+pub mod midi;
 pub mod osc;
+pub mod resolume;
 pub mod setlist;
 pub mod sacn;
+pub mod websocket;
 
 pub trait OutputModule {
 
     fn pre_update(&mut self) {}
 
+    /// Called once all events of an update have been delivered, also when the update ended early
+    fn post_update(&mut self) {}
+
+    /// Realign anything that follows the master deck on its own, such as a phase, right away
+    fn resync(&mut self) {}
+
+    /// `keeper.delay_compensation` was changed while running, in milliseconds
+    fn delay_compensation_changed(&mut self, _ms: f32) {}
+
+    /// Single entry point for everything the keeper outputs. New signals are only delivered here,
+    /// the default forwards the original signals to the per-value methods below.
+    fn on_event(&mut self, target: Target, event: &Event) {
+        dispatch_legacy(self, target, event);
+    }
+
     fn bpm_changed(&mut self, _bpm: f32, _deck: usize) {}
     fn bpm_changed_master(&mut self, _bpm: f32) {}
 
@@ -37,9 +60,51 @@ pub trait OutputModule {
     fn next_phrase_in(&mut self, _beats: i32, _deck: usize) {}
     fn next_phrase_in_master(&mut self, _beats: i32) {}
 
+    fn masterdeck_changed(&mut self, _deck: usize) {}
+
     fn slow_update(&mut self) {}
 }
 
+/// Adapter from events to the per-value methods of `OutputModule`.
+/// Modules overriding `on_event` can fall back to this for the events they don't handle themselves.
+pub fn dispatch_legacy<M: OutputModule + ?Sized>(module: &mut M, target: Target, event: &Event) {
+    match (target, event) {
+        (Target::Deck(deck), Event::Bpm(bpm)) => module.bpm_changed(*bpm, deck),
+        (Target::Master, Event::Bpm(bpm)) => module.bpm_changed_master(*bpm),
+        (Target::Deck(deck), Event::OriginalBpm(bpm)) => module.original_bpm_changed(*bpm, deck),
+        (Target::Master, Event::OriginalBpm(bpm)) => module.original_bpm_changed_master(*bpm),
+        (Target::Deck(deck), Event::Beat(beat)) => module.beat_update(*beat, deck),
+        (Target::Master, Event::Beat(beat)) => module.beat_update_master(*beat),
+        (Target::Deck(deck), Event::Time(time)) => module.time_update(*time, deck),
+        (Target::Master, Event::Time(time)) => module.time_update_master(*time),
+        (Target::Deck(deck), Event::Track(track)) => module.track_changed(track, deck),
+        (Target::Master, Event::Track(track)) => module.track_changed_master(track),
+        (Target::Deck(deck), Event::Phrase(phrase)) => module.phrase_changed(phrase, deck),
+        (Target::Master, Event::Phrase(phrase)) => module.phrase_changed_master(phrase),
+        (Target::Deck(deck), Event::NextPhrase(phrase)) => module.next_phrase_changed(phrase, deck),
+        (Target::Master, Event::NextPhrase(phrase)) => module.next_phrase_changed_master(phrase),
+        (Target::Deck(deck), Event::NextPhraseIn(beats)) => module.next_phrase_in(*beats, deck),
+        (Target::Master, Event::NextPhraseIn(beats)) => module.next_phrase_in_master(*beats),
+        (_, Event::MasterDeck(deck)) => module.masterdeck_changed(*deck),
+        // Only delivered through on_event
+        (
+            _,
+            Event::TrackBeat(_)
+            | Event::PhraseBeat(_)
+            | Event::BeatCount(_)
+            | Event::TrackLength(_)
+            | Event::TimeRemaining(_)
+            | Event::Progress(_)
+            | Event::NextCue(_)
+            | Event::NextCueIn(_)
+            | Event::CuePassed(_)
+            | Event::Energy(_)
+            | Event::Bands(_)
+            | Event::PlayState(_),
+        ) => (),
+    }
+}
+
 pub struct ModuleDefinition {
     pub config_name: String,
     pub pretty_name: String,
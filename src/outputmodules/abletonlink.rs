@@ -1,15 +1,31 @@
 use rusty_link::{AblLink, SessionState};
 
-use crate::{config::Config, log::ScopedLogger, outputmodules::OutputModule};
+use crate::{config::Config, events::{Event, PlayState, Target}, log::ScopedLogger, outputmodules::OutputModule};
 
-use super::ModuleCreateOutput;
+use super::{dispatch_legacy, ModuleCreateOutput};
+
+/// Which beat counter the Link phase follows
+#[derive(PartialEq)]
+enum PhaseSource {
+    /// Position in the 4/4 bar of the beatgrid
+    Bar,
+    /// Beats since the start of the track
+    Track,
+    /// Beats since the start of the current phrase
+    Phrase,
+}
 
 pub struct AbletonLink {
     link: AblLink,
     state: SessionState,
     last_num_links: u64,
     logger: ScopedLogger,
-    last_beat: f32,
+    play_state: PlayState,
+    phase: f32,
+    phase_source: PhaseSource,
+    quantum: f32,
+    bpm: f32,
+    quantized_start: bool,
     cumulative_error: f32,
     cumulative_error_tolerance: f32,
 }
@@ -18,18 +34,46 @@ impl AbletonLink {
     pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
         let link = AblLink::new(120.);
         link.enable(false);
+        let start_stop_sync = conf.get_or_default("start_stop_sync", false);
+        link.enable_start_stop_sync(start_stop_sync);
+        if start_stop_sync {
+            logger.info("Start/stop sync enabled");
+        }
 
         let mut state = SessionState::new();
         link.capture_app_session_state(&mut state);
 
         link.enable(true);
 
+        let mut quantum = conf.get_or_default("quantum", 4.0);
+        if quantum <= 0. {
+            logger.warn("quantum must be positive, using 4");
+            quantum = 4.;
+        }
+        let phase_source = match conf.get_or_default("phase_source", "bar".to_string()).as_str() {
+            "bar" => PhaseSource::Bar,
+            "track" => PhaseSource::Track,
+            "phrase" => PhaseSource::Phrase,
+            other => {
+                logger.err(&format!("Unknown phase source '{other}', using bar"));
+                PhaseSource::Bar
+            }
+        };
+        if phase_source == PhaseSource::Bar && 4. % quantum != 0. {
+            logger.warn(&format!("A quantum of {quantum} does not fit in a 4/4 bar, use phase_source track or phrase"));
+        }
+
         Ok(Box::new(AbletonLink {
             link,
             state,
             last_num_links: 9999,
             logger,
-            last_beat: 0.,
+            play_state: PlayState::Paused,
+            phase: 0.,
+            phase_source,
+            quantum,
+            bpm: 120.,
+            quantized_start: conf.get_or_default("quantized_start", true),
             cumulative_error: 0.0,
             cumulative_error_tolerance: conf.get_or_default("cumulative_error_tolerance", 0.05),
         }))
@@ -37,31 +81,32 @@ impl AbletonLink {
 }
 
 impl OutputModule for AbletonLink {
+    fn on_event(&mut self, target: Target, event: &Event) {
+        match (target, event) {
+            (Target::Master, Event::PlayState(state)) => self.play_state_changed(*state),
+            (Target::Master, Event::TrackBeat(beat)) if self.phase_source == PhaseSource::Track => self.sync_phase(*beat),
+            (Target::Master, Event::PhraseBeat(beat)) if self.phase_source == PhaseSource::Phrase => self.sync_phase(*beat),
+            _ => dispatch_legacy(self, target, event),
+        }
+    }
+
     fn bpm_changed_master(&mut self, bpm: f32) {
+        self.bpm = bpm;
         self.state.set_tempo(bpm as f64, self.link.clock_micros());
         self.link.commit_app_session_state(&self.state);
     }
 
     fn beat_update_master(&mut self, beat: f32) {
-        // Let link free-wheel if not playing
-        if self.last_beat == beat {
-            return;
+        if self.phase_source == PhaseSource::Bar {
+            self.sync_phase(beat);
         }
-        // let target_beat = (beat as f64) % 4.;
+    }
 
-        let link_beat = self.state.beat_at_time(self.link.clock_micros(), 4.0) as f32;
-        let diff = (link_beat - beat + 2.0) % 4.0 - 2.0;
-        // println!("{diff}");
-        self.cumulative_error += diff;
-        // println!("cumerr {}", self.cumulative_error);
-        if self.cumulative_error.abs() > self.cumulative_error_tolerance {
-            self.cumulative_error = 0.0;
-            // println!("SET -----------------------------------------------------");
-            self.state
-                .force_beat_at_time(beat.into(), self.link.clock_micros() as i64, 4.);
-            self.link.commit_app_session_state(&self.state);
-        }
-        self.last_beat = beat;
+    fn resync(&mut self) {
+        self.cumulative_error = 0.0;
+        self.state
+            .force_beat_at_time(self.phase.into(), self.link.clock_micros() as i64, self.quantum as f64);
+        self.link.commit_app_session_state(&self.state);
     }
 
     fn slow_update(&mut self) {
@@ -72,3 +117,45 @@ impl OutputModule for AbletonLink {
         }
     }
 }
+
+impl AbletonLink {
+    fn play_state_changed(&mut self, state: PlayState) {
+        let was_playing = self.play_state.is_playing();
+        let playing = state.is_playing();
+        self.play_state = state;
+        if was_playing == playing {
+            return;
+        }
+
+        let now = self.link.clock_micros();
+        if playing && self.quantized_start {
+            // Start peers on the next quantum boundary, i.e. the next bar or phrase
+            let beats_to_boundary = (self.quantum - self.phase) % self.quantum;
+            let start = now + (beats_to_boundary / self.bpm * 60. * 1_000_000.) as i64;
+            self.state
+                .set_is_playing_and_request_beat_at_time(true, start as u64, 0., self.quantum as f64);
+        } else {
+            self.state.set_is_playing(playing, now as u64);
+        }
+        self.link.commit_app_session_state(&self.state);
+    }
+
+    fn sync_phase(&mut self, beat: f32) {
+        let quantum = self.quantum;
+        self.phase = beat.rem_euclid(quantum);
+        // Let link free-wheel if not playing
+        if !self.play_state.is_playing() {
+            return;
+        }
+
+        let link_beat = self.state.beat_at_time(self.link.clock_micros(), quantum as f64) as f32;
+        let diff = (link_beat - self.phase + quantum / 2.).rem_euclid(quantum) - quantum / 2.;
+        self.cumulative_error += diff;
+        if self.cumulative_error.abs() > self.cumulative_error_tolerance {
+            self.cumulative_error = 0.0;
+            self.state
+                .force_beat_at_time(self.phase.into(), self.link.clock_micros() as i64, quantum as f64);
+            self.link.commit_app_session_state(&self.state);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::{config::Config, events::{Event, Target}, log::ScopedLogger};
+use super::dmx::DmxSlots;
+use super::ModuleCreateOutput;
+use super::OutputModule;
+
+const ARTNET_PORT: u16 = 6454;
+const OP_DMX: u16 = 0x5000;
+const PROTOCOL_VERSION: u16 = 14;
+
+/// Art-Net output module, sending ArtDmx packets over plain UDP
+///
+/// Config keys (with defaults):
+/// - `source` (String): local bind address. Default: "0.0.0.0:0".
+/// - `mode` (String): "broadcast" (default) or "unicast".
+/// - `broadcast_address` (String): address to broadcast to. Default: "255.255.255.255".
+/// - `targets` (String): comma-separated IPv4 list for unicast, port 6454 unless given.
+/// - `net` (u8): Art-Net net, 0..=127, default 0.
+/// - `subnet` (u8): Art-Net subnet, 0..=15, default 0.
+/// - `universe` (u8): universe within the subnet, 0..=15, default 0.
+///
+/// See `DmxSlots` for `start_channel`, `layout`, `map.<channel>` and the channel layout.
+///
+pub struct ArtNet {
+    socket: UdpSocket,
+    targets: Vec<SocketAddr>,
+    net: u8,
+    sub_uni: u8,
+    sequence: u8,
+    slots: DmxSlots,
+    // Send at the end of the update
+    dirty: bool,
+    logger: ScopedLogger,
+}
+
+impl ArtNet {
+    pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
+        let source = conf.get_or_default("source", String::from("0.0.0.0:0"));
+        let socket = match UdpSocket::bind(&source) {
+            Ok(s) => s,
+            Err(e) => {
+                logger.err(&format!("Failed to bind Art-Net socket to {source}: {e}"));
+                return Err(());
+            }
+        };
+
+        // Destinations
+        let mode_str = conf.get_or_default("mode", String::from("broadcast"));
+        let destinations = match mode_str.to_ascii_lowercase().as_str() {
+            "unicast" => conf.get_or_default("targets", String::new()),
+            "broadcast" => {
+                if let Err(e) = socket.set_broadcast(true) {
+                    logger.err(&format!("Failed to enable broadcast: {e}"));
+                    return Err(());
+                }
+                conf.get_or_default("broadcast_address", String::from("255.255.255.255"))
+            }
+            _ => {
+                logger.err(&format!("Unknown mode '{mode_str}', expected broadcast or unicast"));
+                return Err(());
+            }
+        };
+        let mut targets: Vec<SocketAddr> = Vec::new();
+        for ip in destinations.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            // Default to the Art-Net port if no port was given
+            let sa = if ip.contains(':') { ip.to_string() } else { format!("{}:{}", ip, ARTNET_PORT) };
+            match sa.parse::<SocketAddr>() {
+                Ok(sa) => targets.push(sa),
+                Err(_) => logger.err(&format!("Invalid Art-Net target address '{}'", ip)),
+            }
+        }
+        if targets.is_empty() {
+            logger.err("No valid Art-Net targets");
+            return Err(());
+        }
+
+        // Port-Address: 7 bit net, 4 bit subnet and 4 bit universe
+        let mut net: u8 = conf.get_or_default("net", 0u8);
+        if net > 127 {
+            logger.warn("net > 127 invalid, using 127");
+            net = 127;
+        }
+        let mut subnet: u8 = conf.get_or_default("subnet", 0u8);
+        if subnet > 15 {
+            logger.warn("subnet > 15 invalid, using 15");
+            subnet = 15;
+        }
+        let mut universe: u8 = conf.get_or_default("universe", 0u8);
+        if universe > 15 {
+            logger.warn("universe > 15 invalid, using 15");
+            universe = 15;
+        }
+
+        let slots = DmxSlots::new(&conf, logger.clone(), Target::Master);
+
+        logger.info(&format!(
+            "Art-Net config: net={}, subnet={}, universe={}, channels={:?}, mode={}, targets={:?}",
+            net,
+            subnet,
+            universe,
+            slots.channels(),
+            mode_str,
+            targets
+        ));
+
+        Ok(Box::new(ArtNet {
+            socket,
+            targets,
+            net,
+            sub_uni: (subnet << 4) | universe,
+            sequence: 0,
+            slots,
+            dirty: false,
+            logger,
+        }))
+    }
+
+    fn packet(&self) -> Vec<u8> {
+        let data = self.slots.slots();
+        // The data length has to be even
+        let len = data.len() + data.len() % 2;
+
+        let mut packet = Vec::with_capacity(18 + len);
+        packet.extend_from_slice(b"Art-Net\0");
+        packet.extend_from_slice(&OP_DMX.to_le_bytes());
+        packet.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        packet.push(self.sequence);
+        packet.push(0); // Physical input port, informational only
+        packet.push(self.sub_uni);
+        packet.push(self.net);
+        packet.extend_from_slice(&(len as u16).to_be_bytes());
+        packet.extend_from_slice(data);
+        packet.resize(18 + len, 0);
+        packet
+    }
+
+    fn send(&mut self) {
+        // Sequence 0 disables reordering on the receiver, so wrap around to 1
+        self.sequence = self.sequence.wrapping_add(1).max(1);
+        let packet = self.packet();
+        for dst in &self.targets {
+            if let Err(e) = self.socket.send_to(&packet, dst) {
+                self.logger.err(&format!("Failed to send Art-Net packet to {dst}: {e}"));
+            }
+        }
+        self.logger.debug(&format!(
+            "sending to {} targets, net {} sub-uni {} ({} bytes)",
+            self.targets.len(), self.net, self.sub_uni, packet.len()
+        ));
+    }
+}
+
+impl OutputModule for ArtNet {
+    fn on_event(&mut self, target: Target, event: &Event) {
+        self.dirty |= self.slots.update(target, event);
+    }
+
+    /// One packet per update, however many slots changed
+    fn post_update(&mut self) {
+        if self.dirty {
+            self.dirty = false;
+            self.send();
+        }
+    }
+
+    fn slow_update(&mut self) {
+        //this is done as a keepalive.
+        self.dirty = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use crate::log::Logger;
+
+    fn artnet(conf: &str, targets: Vec<SocketAddr>) -> ArtNet {
+        let logger = ScopedLogger::new(&Rc::new(Logger::new(false)), "Art-Net");
+        let conf = Config::parse(conf, logger.clone());
+        ArtNet {
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            targets,
+            net: 0x12,
+            sub_uni: 0x34,
+            sequence: 7,
+            slots: DmxSlots::new(&conf, logger.clone(), Target::Master),
+            dirty: false,
+            logger,
+        }
+    }
+
+    #[test]
+    fn artdmx_packet_layout() {
+        let mut artnet = artnet("start_channel 2", vec![]);
+        artnet.slots.update(Target::Master, &Event::Bpm(128.));
+
+        let packet = artnet.packet();
+        assert_eq!(&packet[0..8], b"Art-Net\0");
+        assert_eq!(&packet[8..10], &[0x00, 0x50]); // OpDmx, little endian
+        assert_eq!(&packet[10..12], &[0, 14]); // Protocol version, big endian
+        assert_eq!(packet[12], 7); // Sequence
+        assert_eq!(packet[13], 0); // Physical
+        assert_eq!(packet[14], 0x34); // SubUni
+        assert_eq!(packet[15], 0x12); // Net
+        // Three slots in use, padded to an even length
+        assert_eq!(&packet[16..18], &[0, 4]);
+        assert_eq!(&packet[18..], &[0, 128, 0, 0]);
+    }
+
+    #[test]
+    fn sends_once_per_update() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let mut artnet = artnet("start_channel 1", vec![receiver.local_addr().unwrap()]);
+
+        artnet.pre_update();
+        artnet.on_event(Target::Master, &Event::Bpm(128.));
+        artnet.on_event(Target::Master, &Event::Beat(1.));
+        artnet.post_update();
+        // Nothing changed, nothing to send
+        artnet.pre_update();
+        artnet.on_event(Target::Master, &Event::Bpm(128.));
+        artnet.post_update();
+
+        let mut buf = [0u8; 600];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[18..len], &[128, 1]);
+        assert!(receiver.recv(&mut buf).is_err());
+    }
+}
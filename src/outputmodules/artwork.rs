@@ -0,0 +1,201 @@
+use std::fs;
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use crate::artwork::{find_artwork, Artwork};
+use crate::beatkeeper::TrackInfo;
+use crate::config::Config;
+use crate::log::ScopedLogger;
+
+use super::http::{self, Request};
+use super::osc::OscSender;
+use super::{ModuleCreateOutput, OutputModule};
+
+// Extensions the cover can be written with, one per image type `Artwork` detects
+const EXTENSIONS: [&str; 2] = ["jpg", "png"];
+
+/// Outcome of looking up and exporting the artwork of a track on the worker thread
+struct Lookup {
+    title: String,
+    artwork: Option<Arc<Artwork>>,
+    // File the cover was written to, with the extension of its image type
+    path: Option<String>,
+    error: Option<String>,
+}
+
+/// Exports the cover art of the master track to a file, serves it over HTTP and announces it over OSC
+pub struct ArtworkOutput {
+    url: Option<String>,
+    current: Arc<Mutex<Option<Arc<Artwork>>>>,
+    osc: Option<OscSender>,
+    changes: u32,
+    // Reading covers from audio files is slow, so it's done on a worker thread
+    lookup_tx: mpsc::Sender<TrackInfo>,
+    lookup_rx: mpsc::Receiver<Lookup>,
+    logger: ScopedLogger,
+}
+
+impl ArtworkOutput {
+    pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
+        let filename = conf.get_or_default("filename", "artwork_master".to_string());
+        let current = Arc::new(Mutex::new(None));
+
+        let http_address = conf.get_or_default("http_address", "127.0.0.1:4470".to_string());
+        let url = if http_address.is_empty() {
+            None
+        } else {
+            let listener = match TcpListener::bind(&http_address) {
+                Ok(l) => l,
+                Err(e) => {
+                    logger.err(&format!("Failed to start HTTP server on {http_address}: {e}"));
+                    return Err(());
+                }
+            };
+            let served = current.clone();
+            http::serve(listener, move |stream, request| serve(&stream, request, &served));
+            let url = format!("http://{http_address}");
+            logger.info(&format!("Serving artwork on {url}/{}", file_name(&filename)));
+            Some(url)
+        };
+
+        let osc = if conf.get_or_default("destination", "127.0.0.1:4460".to_string()).is_empty() {
+            None
+        } else {
+            let socket = match UdpSocket::bind("0.0.0.0:0") {
+                Ok(s) => s,
+                Err(e) => {
+                    logger.err(&format!("Failed to open OSC socket: {e}"));
+                    return Err(());
+                }
+            };
+            // Same destination, prefix, bundle and map keys as an OSC target
+            Some(OscSender::new(&conf, &socket, "127.0.0.1:4460", logger.clone())?)
+        };
+
+        let (lookup_tx, worker_rx) = mpsc::channel::<TrackInfo>();
+        let (worker_tx, lookup_rx) = mpsc::channel();
+        let (export_filename, exported) = (filename, current.clone());
+        thread::spawn(move || {
+            while let Ok(mut track) = worker_rx.recv() {
+                // Only the latest track matters when several changed in the meantime
+                while let Ok(newer) = worker_rx.try_recv() {
+                    track = newer;
+                }
+                let lookup = export(&track, &export_filename);
+                *exported.lock().unwrap_or_else(|e| e.into_inner()) = lookup.artwork.clone();
+                if worker_tx.send(lookup).is_err() {
+                    break; // Module is gone
+                }
+            }
+        });
+
+        Ok(Box::new(ArtworkOutput {
+            url,
+            current,
+            osc,
+            changes: 0,
+            lookup_tx,
+            lookup_rx,
+            logger,
+        }))
+    }
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Find the artwork of `track` and write it to `filename` with the extension of its image type.
+/// Covers of other types are removed, so the previous track's cover isn't left behind.
+fn export(track: &TrackInfo, filename: &str) -> Lookup {
+    let artwork = find_artwork(track).map(Arc::new);
+    let path = artwork
+        .as_ref()
+        .map(|art| Path::new(filename).with_extension(art.extension()).to_string_lossy().into_owned());
+
+    let mut error = None;
+    for extension in EXTENSIONS {
+        let other = Path::new(filename).with_extension(extension).to_string_lossy().into_owned();
+        if Some(&other) == path.as_ref() {
+            continue;
+        }
+        match fs::remove_file(&other) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => error = Some(format!("Failed to remove {other}: {e}")),
+            _ => (),
+        }
+    }
+    if let (Some(art), Some(path)) = (&artwork, &path) {
+        if let Err(e) = fs::write(path, &art.data) {
+            error = Some(format!("Failed to write artwork to {path}: {e}"));
+        }
+    }
+
+    Lookup {
+        title: track.title.clone(),
+        artwork,
+        path,
+        error,
+    }
+}
+
+fn serve(stream: &TcpStream, request: Request, current: &Mutex<Option<Arc<Artwork>>>) -> std::io::Result<()> {
+    if request.method != "GET" {
+        return http::respond(stream, "405 Method Not Allowed", "text/plain", b"");
+    }
+    // Don't hold the lock while writing, the client may be slow
+    let artwork = current.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    match artwork {
+        Some(art) => http::respond(stream, "200 OK", art.mime, &art.data),
+        None => http::respond(stream, "404 Not Found", "text/plain", b""),
+    }
+}
+
+impl OutputModule for ArtworkOutput {
+    fn track_changed_master(&mut self, track: &TrackInfo) {
+        if self.lookup_tx.send(track.clone()).is_err() {
+            self.logger.err("Artwork worker stopped");
+        }
+    }
+
+    fn pre_update(&mut self) {
+        if let Some(osc) = &self.osc {
+            osc.begin_update();
+        }
+        while let Ok(lookup) = self.lookup_rx.try_recv() {
+            if let Some(e) = &lookup.error {
+                self.logger.err(e);
+            }
+            if lookup.artwork.is_none() {
+                self.logger.debug(&format!("No artwork found for {}", lookup.title));
+            }
+
+            // Receivers cache by URL, so make it unique for every track
+            self.changes = self.changes.wrapping_add(1);
+            let location = match (&self.url, &lookup.path) {
+                (_, None) => String::new(),
+                (Some(url), Some(path)) => format!("{url}/{}?{}", file_name(path), self.changes),
+                (None, Some(path)) => path.clone(),
+            };
+            if let Some(osc) = &self.osc {
+                osc.send_string("/master/track/artwork", &location);
+            }
+        }
+    }
+
+    fn post_update(&mut self) {
+        if let Some(osc) = &self.osc {
+            osc.end_update();
+        }
+    }
+
+    fn delay_compensation_changed(&mut self, ms: f32) {
+        if let Some(osc) = &self.osc {
+            osc.set_delay_compensation(ms);
+        }
+    }
+}
@@ -0,0 +1,454 @@
+use std::ops::RangeInclusive;
+
+use crate::{config::Config, events::{Bands, BeatCount, Event, Target}, log::ScopedLogger, utils::PhraseParser};
+
+/// Channel layout shared by the DMX output modules (sACN and Art-Net)
+///
+/// Config keys (with defaults):
+/// - `start_channel` (u16): DMX start/offset of the layout, 1..=511 for `default` and 1..=498 for `extended`, default 1.
+/// - `layout` (String): `default` or `extended`, default `default`.
+/// - `map.<channel>` (String): `[deck<n>.]<signal> [16bit] [range=<min>:<max>]`. Assigns a signal
+///   to a 1-indexed DMX channel. When any channel is mapped the layout is not used.
+///
+/// Signals without a deck prefix, and the layout, follow the target the slots are created for.
+///
+/// Layout (starting at `start_channel`):
+/// - +0 : BPM (u8). Capped to 250. Values > 250 are sent as 250.
+/// - +1 : Beat absolute counter (u8). Wraps 0..=255.
+///
+/// `extended` continues with:
+/// - +2 : Play state (u8). 0 paused, 1 playing, 2 scratching, 3 reverse.
+/// - +3 : Bar number in the track (u8). Wraps 0..=255.
+/// - +4 : Beat in bar (u8). 1..=4, 0 without a beatgrid.
+/// - +5 : Beats until the next cue (u8). Capped to 255, 0 when there is no cue ahead.
+/// - +6 : Passed cue counter (u8). Incremented every time the playhead passes a cue, wraps 0..=255.
+/// - +7 : Hot cue number of the last passed cue (u8). 1 for A, 2 for B..., 0 for memory cues.
+/// - +8..=+10 : Colour of the last passed cue (u8 red, green, blue). 0 if the cue has no colour.
+/// - +11 : Waveform energy at the playhead (u8). 0..=255.
+/// - +12..=+14 : Low, mid and high waveform colour bands (u8). 0..=255, 0 without a colour waveform.
+///
+pub struct DmxSlots {
+    dmx: [u8; 513], // index 0 is start code = 0, then 512 DMX slots
+    target: Target,
+    mappings: Vec<Mapping>,
+    states: Vec<SignalState>, // master, then one per deck
+    channels: RangeInclusive<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Signal {
+    Bpm,
+    OriginalBpm,
+    BeatCounter,
+    BeatPhase,
+    BarPhase,
+    BeatInBar,
+    Bar,
+    Phrase,
+    NextPhrase,
+    NextPhraseIn,
+    PlayState,
+    MasterDeck,
+    Progress,
+    NextCueIn,
+    CueCounter,
+    CueHotCue,
+    CueRed,
+    CueGreen,
+    CueBlue,
+    Energy,
+    BandLow,
+    BandMid,
+    BandHigh,
+}
+
+impl Signal {
+    const ALL: [(&'static str, Signal); 23] = [
+        ("bpm", Signal::Bpm),
+        ("original_bpm", Signal::OriginalBpm),
+        ("beat_counter", Signal::BeatCounter),
+        ("beat_phase", Signal::BeatPhase),
+        ("bar_phase", Signal::BarPhase),
+        ("beat_in_bar", Signal::BeatInBar),
+        ("bar", Signal::Bar),
+        ("phrase", Signal::Phrase),
+        ("next_phrase", Signal::NextPhrase),
+        ("next_phrase_in", Signal::NextPhraseIn),
+        ("play_state", Signal::PlayState),
+        ("master_deck", Signal::MasterDeck),
+        ("progress", Signal::Progress),
+        ("next_cue_in", Signal::NextCueIn),
+        ("cue_counter", Signal::CueCounter),
+        ("cue_hot_cue", Signal::CueHotCue),
+        ("cue_red", Signal::CueRed),
+        ("cue_green", Signal::CueGreen),
+        ("cue_blue", Signal::CueBlue),
+        ("energy", Signal::Energy),
+        ("band_low", Signal::BandLow),
+        ("band_mid", Signal::BandMid),
+        ("band_high", Signal::BandHigh),
+    ];
+
+    fn from_name(name: &str) -> Option<Signal> {
+        Self::ALL.iter().find(|(n, _)| *n == name).map(|(_, s)| *s)
+    }
+
+    /// Signals with a natural range of 0..1, scaled to the full channel
+    fn is_fraction(self) -> bool {
+        matches!(
+            self,
+            Signal::BeatPhase | Signal::BarPhase | Signal::Progress | Signal::Energy | Signal::BandLow | Signal::BandMid | Signal::BandHigh
+        )
+    }
+
+    /// Counters wrap around instead of saturating
+    fn wraps(self) -> bool {
+        matches!(self, Signal::BeatCounter | Signal::Bar | Signal::CueCounter)
+    }
+
+    fn value(self, state: &SignalState) -> f32 {
+        match self {
+            Signal::Bpm => state.bpm,
+            Signal::OriginalBpm => state.original_bpm,
+            Signal::BeatCounter => state.beat_counter as f32,
+            Signal::BeatPhase => state.beat.rem_euclid(1.),
+            Signal::BarPhase => state.beat.rem_euclid(4.) / 4.,
+            Signal::BeatInBar => state.count.beat_in_bar as f32,
+            Signal::Bar => state.count.bar as f32,
+            Signal::Phrase => state.phrase as f32,
+            Signal::NextPhrase => state.next_phrase as f32,
+            Signal::NextPhraseIn => state.next_phrase_in as f32,
+            Signal::PlayState => state.play_state as f32,
+            Signal::MasterDeck => state.master_deck as f32,
+            Signal::Progress => state.progress,
+            Signal::NextCueIn => state.next_cue_in as f32,
+            Signal::CueCounter => state.cue_counter as f32,
+            Signal::CueHotCue => state.cue_hot_cue as f32,
+            Signal::CueRed => state.cue_color.0 as f32,
+            Signal::CueGreen => state.cue_color.1 as f32,
+            Signal::CueBlue => state.cue_color.2 as f32,
+            Signal::Energy => state.energy,
+            Signal::BandLow => state.bands.low,
+            Signal::BandMid => state.bands.mid,
+            Signal::BandHigh => state.bands.high,
+        }
+    }
+}
+
+/// Latest values for one target
+#[derive(Default)]
+struct SignalState {
+    bpm: f32,
+    original_bpm: f32,
+    beat: f32,
+    last_beat_floor: Option<i32>,
+    beat_counter: u32,
+    count: BeatCount,
+    phrase: i32,
+    next_phrase: i32,
+    next_phrase_in: i32,
+    play_state: u8,
+    master_deck: usize,
+    progress: f32,
+    next_cue_in: i32,
+    cue_counter: u32,
+    cue_hot_cue: u8,
+    cue_color: (u8, u8, u8),
+    energy: f32,
+    bands: Bands,
+}
+
+impl SignalState {
+    fn update(&mut self, event: &Event) {
+        match event {
+            Event::Bpm(bpm) => self.bpm = *bpm,
+            Event::OriginalBpm(bpm) => self.original_bpm = *bpm,
+            Event::Beat(beat) => {
+                self.beat = *beat;
+                let floor_now = beat.floor() as i32;
+                if self.last_beat_floor != Some(floor_now) {
+                    self.last_beat_floor = Some(floor_now);
+                    self.beat_counter = self.beat_counter.wrapping_add(1);
+                }
+            }
+            Event::BeatCount(count) => self.count = *count,
+            Event::Phrase(phrase) => self.phrase = PhraseParser::phrase_name_to_index(phrase),
+            Event::NextPhrase(phrase) => self.next_phrase = PhraseParser::phrase_name_to_index(phrase),
+            Event::NextPhraseIn(beats) => self.next_phrase_in = *beats,
+            Event::PlayState(state) => self.play_state = state.to_index(),
+            Event::MasterDeck(deck) => self.master_deck = *deck,
+            Event::Progress(progress) => self.progress = *progress,
+            Event::NextCueIn(beats) => self.next_cue_in = *beats,
+            Event::NextCue(None) => self.next_cue_in = 0,
+            Event::CuePassed(cue) => {
+                self.cue_counter = self.cue_counter.wrapping_add(1);
+                self.cue_hot_cue = cue.hot_cue;
+                self.cue_color = cue.color.unwrap_or((0, 0, 0));
+            }
+            Event::Energy(energy) => self.energy = *energy,
+            Event::Bands(bands) => self.bands = *bands,
+            _ => (),
+        }
+    }
+}
+
+/// A signal assigned to a channel, or to a coarse/fine channel pair
+struct Mapping {
+    channel: usize, // 1..=512
+    target: Target,
+    signal: Signal,
+    wide: bool,
+    range: Option<(f32, f32)>,
+}
+
+impl Mapping {
+    fn new(channel: usize, target: Target, signal: Signal) -> Self {
+        Mapping {
+            channel,
+            target,
+            signal,
+            wide: false,
+            range: None,
+        }
+    }
+
+    /// Parse `[deck<n>.]<signal> [16bit] [range=<min>:<max>]`
+    fn parse(channel: usize, default_target: Target, spec: &str) -> Result<Self, String> {
+        let mut parts = spec.split_whitespace();
+        let name = parts.next().ok_or("no signal given")?;
+        let (target, name) = match name.strip_prefix("deck").and_then(|rest| rest.split_once('.')) {
+            Some((deck, name)) => (Target::Deck(deck.parse().map_err(|_| format!("invalid deck '{deck}'"))?), name),
+            None => (default_target, name),
+        };
+        let signal = Signal::from_name(name).ok_or(format!("unknown signal '{name}'"))?;
+
+        let mut mapping = Mapping::new(channel, target, signal);
+        for option in parts {
+            if option == "16bit" {
+                mapping.wide = true;
+            } else if let Some(range) = option.strip_prefix("range=") {
+                let parsed = range.split_once(':').and_then(|(min, max)| Some((min.parse::<f32>().ok()?, max.parse::<f32>().ok()?)));
+                match parsed {
+                    Some((min, max)) if min != max => mapping.range = Some((min, max)),
+                    _ => return Err(format!("invalid range '{range}', expected <min>:<max>")),
+                }
+            } else {
+                return Err(format!("unknown option '{option}'"));
+            }
+        }
+        if mapping.last_channel() > 512 {
+            return Err("16 bit channels need two channels, the last is 512".to_string());
+        }
+        Ok(mapping)
+    }
+
+    fn last_channel(&self) -> usize {
+        if self.wide { self.channel + 1 } else { self.channel }
+    }
+
+    fn render(&self, state: &SignalState) -> u16 {
+        let full: f32 = if self.wide { 65535. } else { 255. };
+        let value = self.signal.value(state);
+        let out = match self.range {
+            Some((min, max)) => ((value - min) / (max - min)).clamp(0., 1.) * full,
+            None if self.signal.is_fraction() => value.clamp(0., 1.) * full,
+            None if self.signal.wraps() => return (value as i64).rem_euclid(full as i64 + 1) as u16,
+            // Kept from the original layout, where the tempo channel never exceeded 250
+            None if self.signal == Signal::Bpm && !self.wide => value.clamp(0., 250.),
+            None => value.clamp(0., full),
+        };
+        out.round() as u16
+    }
+}
+
+impl DmxSlots {
+    const DEFAULT_LAYOUT: [Signal; 2] = [Signal::Bpm, Signal::BeatCounter];
+
+    const EXTENDED_LAYOUT: [Signal; 15] = [
+        Signal::Bpm,
+        Signal::BeatCounter,
+        Signal::PlayState,
+        Signal::Bar,
+        Signal::BeatInBar,
+        Signal::NextCueIn,
+        Signal::CueCounter,
+        Signal::CueHotCue,
+        Signal::CueRed,
+        Signal::CueGreen,
+        Signal::CueBlue,
+        Signal::Energy,
+        Signal::BandLow,
+        Signal::BandMid,
+        Signal::BandHigh,
+    ];
+
+    pub fn new(conf: &Config, logger: ScopedLogger, target: Target) -> Self {
+        let mut mappings = vec![];
+        for (channel, spec) in conf.entries_with_prefix("map.") {
+            if spec.trim().is_empty() {
+                continue;
+            }
+            let parsed = match channel.parse::<usize>() {
+                Ok(channel) if (1..=512).contains(&channel) => Mapping::parse(channel, target, &spec),
+                _ => Err("channel must be 1-512".to_string()),
+            };
+            match parsed {
+                Ok(mapping) => mappings.push(mapping),
+                Err(e) => logger.err(&format!("Invalid mapping 'map.{channel} {spec}': {e}")),
+            }
+        }
+
+        if mappings.is_empty() {
+            let layout: &[Signal] = match conf.get_or_default("layout", "default".to_string()).as_str() {
+                "extended" => &Self::EXTENDED_LAYOUT,
+                "default" => &Self::DEFAULT_LAYOUT,
+                other => {
+                    logger.warn(&format!("Unknown layout '{other}', using default"));
+                    &Self::DEFAULT_LAYOUT
+                }
+            };
+            // Start slot (1-511 for the default layout, so the whole layout fits)
+            let last_start = 513 - layout.len();
+            let mut start_slot: usize = conf.get_or_default("start_channel", 1u16) as usize;
+            if start_slot < 1 {
+                logger.warn("start_channel < 1 invalid, using 1");
+                start_slot = 1;
+            }
+            if start_slot > last_start {
+                logger.warn(&format!("start_channel > {last_start} invalid, using {last_start}"));
+                start_slot = last_start;
+            }
+            mappings = layout
+                .iter()
+                .enumerate()
+                .map(|(offset, signal)| Mapping::new(start_slot + offset, target, *signal))
+                .collect();
+        }
+
+        for (i, a) in mappings.iter().enumerate() {
+            for b in &mappings[i + 1..] {
+                if a.channel <= b.last_channel() && b.channel <= a.last_channel() {
+                    logger.warn(&format!("Channels {} and {} overlap", a.channel, b.channel));
+                }
+            }
+        }
+
+        let first = mappings.iter().map(|m| m.channel).min().unwrap_or(1);
+        let last = mappings.iter().map(|m| m.last_channel()).max().unwrap_or(1);
+        let mut slots = DmxSlots {
+            dmx: [0u8; 513],
+            target,
+            mappings,
+            states: vec![],
+            channels: first..=last,
+        };
+        slots.render(None);
+        slots
+    }
+
+    /// The target signals without a deck prefix follow
+    pub fn target(&self) -> Target {
+        self.target
+    }
+
+    /// The span of channels in use, 1-indexed
+    pub fn channels(&self) -> &RangeInclusive<usize> {
+        &self.channels
+    }
+
+    /// Start code followed by the slots up to the last one in use.
+    /// Only sending up to the last used slot prevents sending the whole universe on update.
+    pub fn packet(&self) -> &[u8] {
+        &self.dmx[..1 + self.channels.end()]
+    }
+
+    /// Same as `packet`, without the start code
+    pub fn slots(&self) -> &[u8] {
+        &self.packet()[1..]
+    }
+
+    /// Copy the mapped channels into a universe buffer shared with other slots
+    pub fn write_into(&self, dmx: &mut [u8; 513]) {
+        for mapping in &self.mappings {
+            let slots = mapping.channel..=mapping.last_channel();
+            dmx[slots.clone()].copy_from_slice(&self.dmx[slots]);
+        }
+    }
+
+    fn state_index(target: Target) -> usize {
+        match target {
+            Target::Master => 0,
+            Target::Deck(deck) => deck + 1,
+        }
+    }
+
+    /// Write the channels of every mapping for `target`, or all of them. Returns true if any changed.
+    fn render(&mut self, target: Option<Target>) -> bool {
+        let empty = SignalState::default();
+        let mut changed = false;
+        for mapping in &self.mappings {
+            if target.is_some_and(|t| t != mapping.target) {
+                continue;
+            }
+            let state = self.states.get(Self::state_index(mapping.target)).unwrap_or(&empty);
+            // Coarse then fine, 8 bit values only use the low byte
+            let value = mapping.render(state).to_be_bytes();
+            let bytes = if mapping.wide { &value[..] } else { &value[1..] };
+            for (i, byte) in bytes.iter().enumerate() {
+                let slot = &mut self.dmx[mapping.channel + i]; // +0 because index 0 is start code
+                changed |= *slot != *byte;
+                *slot = *byte;
+            }
+        }
+        changed
+    }
+
+    /// Update the channels affected by an event. Returns true if any channel changed and the data
+    /// should be sent.
+    pub fn update(&mut self, target: Target, event: &Event) -> bool {
+        let index = Self::state_index(target);
+        if self.states.len() <= index {
+            self.states.resize_with(index + 1, SignalState::default);
+        }
+        self.states[index].update(event);
+        self.render(Some(target))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mappings() {
+        let mapping = Mapping::parse(10, Target::Master, "bpm").unwrap();
+        assert_eq!((mapping.channel, mapping.target, mapping.signal), (10, Target::Master, Signal::Bpm));
+        assert!(!mapping.wide);
+        assert_eq!(mapping.range, None);
+
+        let mapping = Mapping::parse(20, Target::Master, "deck2.energy 16bit range=0.2:0.8").unwrap();
+        assert_eq!((mapping.target, mapping.signal), (Target::Deck(2), Signal::Energy));
+        assert!(mapping.wide);
+        assert_eq!(mapping.last_channel(), 21);
+        assert_eq!(mapping.range, Some((0.2, 0.8)));
+    }
+
+    #[test]
+    fn rejects_invalid_mappings() {
+        assert!(Mapping::parse(1, Target::Master, "").is_err());
+        assert!(Mapping::parse(1, Target::Master, "tempo").is_err());
+        assert!(Mapping::parse(1, Target::Master, "deckx.bpm").is_err());
+        assert!(Mapping::parse(1, Target::Master, "bpm 24bit").is_err());
+        assert!(Mapping::parse(1, Target::Master, "bpm range=1:1").is_err());
+        assert!(Mapping::parse(1, Target::Master, "bpm range=1").is_err());
+        assert!(Mapping::parse(512, Target::Master, "bpm 16bit").is_err());
+    }
+
+    #[test]
+    fn renders_ranges() {
+        let state = SignalState { bpm: 140., energy: 0.5, ..Default::default() };
+        assert_eq!(Mapping::parse(1, Target::Master, "bpm range=120:160").unwrap().render(&state), 128);
+        assert_eq!(Mapping::parse(1, Target::Master, "energy 16bit").unwrap().render(&state), 32768);
+        assert_eq!(Mapping::parse(1, Target::Master, "bpm").unwrap().render(&SignalState { bpm: 300., ..Default::default() }), 250);
+    }
+}
@@ -1,27 +1,43 @@
 use std::fs;
 
-use crate::{config::Config, log::ScopedLogger};
+use crate::{beatkeeper::TrackInfo, config::Config, log::ScopedLogger};
 
 use super::{ModuleCreateOutput, OutputModule};
 
 pub struct File {
     filename: String,
+    fields: Vec<String>,
     logger: ScopedLogger,
 }
 
 impl File {
     pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
+        let fields = conf.get_or_default("fields", "title,artist,album".to_string());
+        let fields = fields
+            .split(',')
+            .map(|x| x.trim().to_string())
+            .filter(|x| {
+                let valid = TrackInfo::FIELDS.contains(&x.as_str());
+                if !valid {
+                    logger.err(&format!("Unknown track field '{x}'"));
+                }
+                valid
+            })
+            .collect();
+
         Ok(Box::new(File {
             filename: conf.get_or_default("filename", "current_track.txt".to_string()),
+            fields,
             logger,
         }))
     }
 }
 impl OutputModule for File {
-    fn track_changed_master(&mut self, track: &crate::beatkeeper::TrackInfo) {
+    fn track_changed_master(&mut self, track: &TrackInfo) {
+        let lines: Vec<String> = self.fields.iter().filter_map(|x| track.field(x)).collect();
         if let Err(e) = fs::write(
             &self.filename,
-            format!("{}\n{}\n{}", track.title, track.artist, track.album),
+            lines.join("\n"),
         ) {
             self.logger.err(&format!("Failed to write to file: {e}"));
         }
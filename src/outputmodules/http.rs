@@ -0,0 +1,112 @@
+// Minimal HTTP/1.1 server for the modules browsers talk to
+//
+// Every connection gets its own thread, so a slow or idle client only holds up itself.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Browsers open connections ahead of time and may never send a request on them
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+// Request line and headers, anything larger is not from a browser
+const MAX_REQUEST_SIZE: u64 = 16 * 1024;
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Value of the header `name`, case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Read the request line and headers, leaving the body unread
+    pub fn parse(reader: impl BufRead) -> io::Result<Self> {
+        let mut lines = reader.lines();
+        let request_line = lines.next().ok_or(io::ErrorKind::UnexpectedEof)??;
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid request line"));
+        };
+
+        let mut headers = vec![];
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+        Ok(Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers,
+        })
+    }
+}
+
+/// Accept connections on `listener` in the background, calling `handler` on a new thread for each request
+pub fn serve<F>(listener: TcpListener, handler: F)
+where
+    F: Fn(TcpStream, Request) -> io::Result<()> + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let handler = handler.clone();
+            thread::spawn(move || {
+                // Errors only affect that one client
+                let _ = read_request(&stream).and_then(|request| handler(stream, request));
+            });
+        }
+    });
+}
+
+fn read_request(stream: &TcpStream) -> io::Result<Request> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    Request::parse(BufReader::new(stream.take(MAX_REQUEST_SIZE)))
+}
+
+/// Write a complete response and let the client close the connection
+pub fn respond(mut stream: &TcpStream, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_request_line_and_headers() {
+        let raw = "GET /state HTTP/1.1\r\nHost: localhost\r\nSec-WebSocket-Key: abc==\r\n\r\nbody";
+        let request = Request::parse(raw.as_bytes()).unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/state");
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert_eq!(request.header("sec-websocket-key"), Some("abc=="));
+        assert_eq!(request.header("upgrade"), None);
+    }
+
+    #[test]
+    fn rejects_empty_request() {
+        assert!(Request::parse("".as_bytes()).is_err());
+        assert!(Request::parse("GET\r\n\r\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,361 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use midir::{MidiOutput, MidiOutputConnection};
+
+use crate::{config::Config, events::{Event, PlayState, Target}, log::ScopedLogger, utils::PhraseParser};
+
+use super::{dispatch_legacy, ModuleCreateOutput, OutputModule};
+
+const CLOCK: u8 = 0xF8;
+const START: u8 = 0xFA;
+const CONTINUE: u8 = 0xFB;
+const STOP: u8 = 0xFC;
+const SONG_POSITION: u8 = 0xF2;
+
+const PPQN: f32 = 24.;
+// Difference between the reported and the running position treated as a jump in the track
+const JUMP_BEATS: f32 = 0.5;
+// How often the clock thread checks for changes while stopped
+const IDLE_WAIT: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy)]
+enum TriggerKind {
+    Note,
+    Cc,
+}
+
+/// Sent when the master beat crosses a multiple of `period`, like the OSC beat triggers
+struct Trigger {
+    period: f32,
+    kind: TriggerKind,
+    number: u8,
+}
+
+/// Shared with the clock thread, which extrapolates the position between keeper updates
+struct ClockState {
+    bpm: f32,
+    /// Last track beat reported by the keeper and when it arrived
+    anchor: (Instant, f32),
+    playing: bool,
+    /// Last pulse sent, `None` to continue from the current position without catching up
+    last_pulse: Option<i64>,
+    send_failed: bool,
+    stop: bool,
+}
+
+impl ClockState {
+    fn beat_at(&self, time: Instant) -> f32 {
+        if !self.playing {
+            return self.anchor.1;
+        }
+        self.anchor.1 + time.saturating_duration_since(self.anchor.0).as_secs_f32() * self.bpm / 60.
+    }
+
+    /// Move the anchor to `time` without changing the position, e.g. before the tempo changes
+    fn reanchor(&mut self, time: Instant) {
+        self.anchor = (time, self.beat_at(time));
+    }
+}
+
+pub struct Midi {
+    conn: Arc<Mutex<MidiOutputConnection>>,
+    logger: ScopedLogger,
+    channel: u8, // 0-15
+    clock: Option<Arc<Mutex<ClockState>>>,
+    transport: bool,
+    phrase_program_change: bool,
+    triggers: Vec<Trigger>,
+    releases: Vec<(TriggerKind, u8)>,
+    last_beat: f32,
+    /// Whether a song position pointer was sent since the last track change or resync
+    synced: bool,
+    play_state: PlayState,
+}
+
+impl Midi {
+    pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
+        let out = match MidiOutput::new("rkbx_link") {
+            Ok(out) => out,
+            Err(e) => {
+                logger.err(&format!("Failed to initialise MIDI: {e}"));
+                return Err(());
+            }
+        };
+
+        let port_name = conf.get_or_default("port", String::new());
+        let conn = if port_name == "virtual" {
+            Self::create_virtual(out, &logger)?
+        } else {
+            let ports = out.ports();
+            let names: Vec<String> = ports.iter().map(|p| out.port_name(p).unwrap_or_default()).collect();
+            logger.debug(&format!("Available MIDI ports: {names:?}"));
+            // Match on part of the name, since names contain client numbers on some systems
+            let Some(index) = names.iter().position(|n| n.contains(&port_name)) else {
+                logger.err(&format!("No MIDI port matching '{port_name}', available ports: {}", names.join(", ")));
+                return Err(());
+            };
+            logger.info(&format!("Sending to MIDI port {}", names[index]));
+            match out.connect(&ports[index], "rkbx_link") {
+                Ok(conn) => conn,
+                Err(e) => {
+                    logger.err(&format!("Failed to open MIDI port: {e}"));
+                    return Err(());
+                }
+            }
+        };
+
+        let mut channel: u8 = conf.get_or_default("channel", 1u8);
+        if !(1..=16).contains(&channel) {
+            logger.warn("channel must be 1-16, using 1");
+            channel = 1;
+        }
+
+        // Comma separated <period>:<note|cc>:<number>, e.g. 1:note:36,4:cc:20
+        let mut triggers = vec![];
+        for trigger in conf.get_or_default("triggers", String::new()).split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            let parts: Vec<&str> = trigger.split(':').collect();
+            let parsed = match parts.as_slice() {
+                [period, kind, number] => {
+                    let kind = match *kind {
+                        "note" => Some(TriggerKind::Note),
+                        "cc" => Some(TriggerKind::Cc),
+                        _ => None,
+                    };
+                    match (period.parse::<f32>(), kind, number.parse::<u8>()) {
+                        (Ok(period), Some(kind), Ok(number)) if period > 0. && number < 128 => Some(Trigger { period, kind, number }),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            match parsed {
+                Some(t) => triggers.push(t),
+                None => logger.err(&format!("Invalid trigger '{trigger}', expected <period>:<note|cc>:<number>")),
+            }
+        }
+
+        let conn = Arc::new(Mutex::new(conn));
+        let clock = if conf.get_or_default("clock", true) {
+            let state = Arc::new(Mutex::new(ClockState {
+                bpm: 120.,
+                anchor: (Instant::now(), 0.),
+                playing: false,
+                last_pulse: None,
+                send_failed: false,
+                stop: false,
+            }));
+            let (conn, state_thread) = (conn.clone(), state.clone());
+            thread::spawn(move || run_clock(conn, state_thread));
+            Some(state)
+        } else {
+            None
+        };
+
+        Ok(Box::new(Midi {
+            conn,
+            logger,
+            channel: channel - 1,
+            clock,
+            transport: conf.get_or_default("transport", true),
+            phrase_program_change: conf.get_or_default("phrase_program_change", false),
+            triggers,
+            releases: vec![],
+            last_beat: 0.,
+            synced: false,
+            play_state: PlayState::Paused,
+        }))
+    }
+
+    #[cfg(unix)]
+    fn create_virtual(out: MidiOutput, logger: &ScopedLogger) -> Result<MidiOutputConnection, ()> {
+        use midir::os::unix::VirtualOutput;
+        match out.create_virtual("rkbx_link") {
+            Ok(conn) => {
+                logger.info("Created virtual MIDI port rkbx_link");
+                Ok(conn)
+            }
+            Err(e) => {
+                logger.err(&format!("Failed to create virtual MIDI port: {e}"));
+                Err(())
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn create_virtual(_out: MidiOutput, logger: &ScopedLogger) -> Result<MidiOutputConnection, ()> {
+        logger.err("Virtual MIDI ports are not supported on Windows, use a loopback driver such as loopMIDI");
+        Err(())
+    }
+
+    fn send(&mut self, message: &[u8]) {
+        if let Err(e) = self.conn.lock().unwrap().send(message) {
+            self.logger.err(&format!("Failed to send MIDI message: {e}"));
+        }
+    }
+
+    /// Position in MIDI beats (16th notes), limited to the 14 bits of the message
+    fn send_song_position(&mut self, track_beat: f32) {
+        let position = ((track_beat.max(0.) * 4.) as u32).min(0x3FFF);
+        self.send(&[SONG_POSITION, (position & 0x7F) as u8, (position >> 7) as u8]);
+    }
+
+    /// Realign the clock thread with the position reported by the keeper
+    fn track_beat_update(&mut self, track_beat: f32) {
+        let Some(clock) = self.clock.clone() else {
+            return;
+        };
+        let mut state = clock.lock().unwrap();
+        let now = Instant::now();
+        let jumped = !self.synced || (state.beat_at(now) - track_beat).abs() > JUMP_BEATS;
+        state.anchor = (now, track_beat);
+        if jumped {
+            state.last_pulse = None;
+            drop(state);
+            self.synced = true;
+            self.send_song_position(track_beat);
+        }
+    }
+
+    fn play_state_changed(&mut self, state: PlayState) {
+        let was_playing = self.play_state.is_playing();
+        self.play_state = state;
+        let mut position = 0.;
+        if let Some(clock) = &self.clock {
+            let mut clock = clock.lock().unwrap();
+            clock.reanchor(Instant::now());
+            clock.playing = state.is_playing();
+            position = clock.anchor.1;
+        }
+        if !self.transport || was_playing == state.is_playing() {
+            return;
+        }
+        if state.is_playing() {
+            // Start always restarts from the top, so continue from the current position otherwise
+            if position <= 0. {
+                self.send(&[START]);
+            } else {
+                self.send(&[CONTINUE]);
+            }
+        } else {
+            self.send(&[STOP]);
+        }
+    }
+
+    fn release_triggers(&mut self) {
+        for (kind, number) in std::mem::take(&mut self.releases) {
+            match kind {
+                TriggerKind::Note => self.send(&[0x80 | self.channel, number, 0]),
+                TriggerKind::Cc => self.send(&[0xB0 | self.channel, number, 0]),
+            }
+        }
+    }
+}
+
+/// Sends the 24 PPQN clock on time, independent of how often the keeper updates
+fn run_clock(conn: Arc<Mutex<MidiOutputConnection>>, state: Arc<Mutex<ClockState>>) {
+    loop {
+        let wait = {
+            let mut state = state.lock().unwrap();
+            if state.stop {
+                return;
+            }
+            if !state.playing || state.bpm <= 0. {
+                IDLE_WAIT
+            } else {
+                let now = Instant::now();
+                let beat = state.beat_at(now);
+                let pulse = (beat * PPQN).floor() as i64;
+                let next = match state.last_pulse {
+                    // Behind by less than a beat, send one pulse and come back right away for the rest
+                    Some(last) if pulse > last && pulse - last <= PPQN as i64 => {
+                        if conn.lock().unwrap().send(&[CLOCK]).is_err() {
+                            state.send_failed = true;
+                        }
+                        last + 1
+                    }
+                    // Position moved back slightly, wait for it to pass the last pulse again
+                    Some(last) if pulse <= last => last,
+                    _ => pulse,
+                };
+                state.last_pulse = Some(next);
+                let until_next = ((next + 1) as f32 / PPQN - beat) * 60. / state.bpm;
+                Duration::from_secs_f32(until_next.max(0.)).min(IDLE_WAIT * 10)
+            }
+        };
+        thread::sleep(wait);
+    }
+}
+
+impl Drop for Midi {
+    fn drop(&mut self) {
+        if let Some(clock) = &self.clock {
+            clock.lock().unwrap().stop = true;
+        }
+    }
+}
+
+impl OutputModule for Midi {
+    fn pre_update(&mut self) {
+        // Triggers are held for one update
+        self.release_triggers();
+
+        if let Some(clock) = &self.clock {
+            if std::mem::take(&mut clock.lock().unwrap().send_failed) {
+                self.logger.err("Failed to send MIDI clock");
+            }
+        }
+    }
+
+    fn on_event(&mut self, target: Target, event: &Event) {
+        match (target, event) {
+            (Target::Master, Event::TrackBeat(beat)) => self.track_beat_update(*beat),
+            (Target::Master, Event::PlayState(state)) => self.play_state_changed(*state),
+            (Target::Master, Event::Track(_)) => {
+                // Position jumps on a new track, resync with a song position pointer
+                self.synced = false;
+                dispatch_legacy(self, target, event);
+            }
+            _ => dispatch_legacy(self, target, event),
+        }
+    }
+
+    fn resync(&mut self) {
+        // Sends a song position pointer on the next update
+        self.synced = false;
+    }
+
+    fn bpm_changed_master(&mut self, bpm: f32) {
+        if let Some(clock) = &self.clock {
+            let mut clock = clock.lock().unwrap();
+            clock.reanchor(Instant::now());
+            clock.bpm = bpm;
+        }
+    }
+
+    fn beat_update_master(&mut self, beat: f32) {
+        let mut pressed = vec![];
+        for trigger in &self.triggers {
+            let d = trigger.period;
+            if beat % d < self.last_beat % d {
+                pressed.push((trigger.kind, trigger.number));
+            }
+        }
+        for (kind, number) in pressed {
+            match kind {
+                TriggerKind::Note => self.send(&[0x90 | self.channel, number, 127]),
+                TriggerKind::Cc => self.send(&[0xB0 | self.channel, number, 127]),
+            }
+            self.releases.push((kind, number));
+        }
+        self.last_beat = beat;
+    }
+
+    fn phrase_changed_master(&mut self, phrase: &str) {
+        if self.phrase_program_change {
+            let program = PhraseParser::phrase_name_to_index(phrase).clamp(0, 127) as u8;
+            self.send(&[0xC0 | self.channel, program]);
+        }
+    }
+}
@@ -1,8 +1,11 @@
-use std::net::UdpSocket;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, SystemTime};
 
-use rosc::{encoder::encode, OscMessage, OscPacket};
+use rosc::{encoder::encode, OscBundle, OscMessage, OscPacket, OscTime};
 
-use crate::{beatkeeper::TrackInfo, config::Config, log::ScopedLogger, utils::PhraseParser};
+use crate::{beatkeeper::TrackInfo, config::Config, cues::Cue, events::{Bands, BeatCount, Event, PlayState, Target}, log::ScopedLogger, utils::PhraseParser};
 
 use super::{ModuleCreateOutput, OutputModule};
 
@@ -23,6 +26,64 @@ impl OutputFormat {
     }
 }
 
+/// Replacement address and value conversion for a message, from `osc.map.<key>`
+struct AddressTemplate {
+    address: String,
+    scale: Option<(f32, f32, f32, f32)>,
+    format: Option<OutputFormat>,
+}
+
+impl AddressTemplate {
+    /// Parse `<address> [scale=<min>:<max>[:<out_min>:<out_max>]] [type=<float|int|string>]`
+    fn parse(spec: &str) -> Result<Self, String> {
+        let mut parts = spec.split_whitespace();
+        let address = parts.next().ok_or("no address given")?.to_string();
+        let mut template = AddressTemplate { address, scale: None, format: None };
+        for option in parts {
+            if let Some(scale) = option.strip_prefix("scale=") {
+                let invalid = || format!("invalid scale '{scale}', expected <min>:<max>[:<out_min>:<out_max>]");
+                let values = scale
+                    .split(':')
+                    .map(|x| x.parse::<f32>().ok().filter(|x| x.is_finite()))
+                    .collect::<Option<Vec<f32>>>()
+                    .ok_or_else(invalid)?;
+                template.scale = match values.as_slice() {
+                    [min, max] if min != max => Some((*min, *max, 0., 1.)),
+                    [min, max, out_min, out_max] if min != max => Some((*min, *max, *out_min, *out_max)),
+                    _ => return Err(invalid()),
+                };
+            } else if let Some(format) = option.strip_prefix("type=") {
+                template.format = Some(OutputFormat::from_str(format).ok_or(format!("unknown type '{format}'"))?);
+            } else {
+                return Err(format!("unknown option '{option}'"));
+            }
+        }
+        Ok(template)
+    }
+
+    /// Scale and convert numeric arguments, strings are left as they are
+    fn apply(&self, args: &mut [rosc::OscType]) {
+        for arg in args.iter_mut() {
+            let (value, is_int) = match arg {
+                rosc::OscType::Float(x) => (*x, false),
+                rosc::OscType::Int(x) => (*x as f32, true),
+                _ => continue,
+            };
+            let value = match self.scale {
+                Some((min, max, out_min, out_max)) => {
+                    out_min + ((value - min) / (max - min)).clamp(0., 1.) * (out_max - out_min)
+                }
+                None => value,
+            };
+            *arg = match (&self.format, is_int) {
+                (Some(OutputFormat::Float), _) | (None, false) => rosc::OscType::Float(value),
+                (Some(OutputFormat::Int), _) | (None, true) => rosc::OscType::Int(value.round() as i32),
+                (Some(OutputFormat::String), _) => rosc::OscType::String(value.to_string()),
+            };
+        }
+    }
+}
+
 struct MessageToggles{
     /*beat: bool,
     beat_master: bool,*/
@@ -36,8 +97,20 @@ struct MessageToggles{
     beat_trigger_autorelease: bool,
     time: bool,
     time_master: bool,
+    progress: bool,
+    progress_master: bool,
     phrase: bool,
     phrase_master: bool,
+    cue: bool,
+    cue_master: bool,
+    energy: bool,
+    energy_master: bool,
+    bands: bool,
+    bands_master: bool,
+    transport: bool,
+    transport_master: bool,
+    beat_count: bool,
+    beat_count_master: bool,
     phrase_output_format: OutputFormat,
 }
 
@@ -71,8 +144,20 @@ impl MessageToggles{
             beat_trigger_autorelease: conf.get_or_default("trigger_autorelease", false),
             time: conf.get_or_default("msg.n/time", false), 
             time_master: conf.get_or_default("msg.master/time", true), 
+            progress: conf.get_or_default("msg.n/progress", false),
+            progress_master: conf.get_or_default("msg.master/progress", true),
             phrase: conf.get_or_default("msg.n/phrase", false), 
             phrase_master:  conf.get_or_default("msg.master/phrase", true),
+            cue: conf.get_or_default("msg.n/cue", false),
+            cue_master: conf.get_or_default("msg.master/cue", true),
+            energy: conf.get_or_default("msg.n/energy", false),
+            energy_master: conf.get_or_default("msg.master/energy", false),
+            bands: conf.get_or_default("msg.n/bands", false),
+            bands_master: conf.get_or_default("msg.master/bands", false),
+            transport: conf.get_or_default("msg.n/transport", false),
+            transport_master: conf.get_or_default("msg.master/transport", true),
+            beat_count: conf.get_or_default("msg.n/beat/count", false),
+            beat_count_master: conf.get_or_default("msg.master/beat/count", true),
             phrase_output_format: {
                 let fmt = conf.get_or_default("phrase_output_format", "string".to_string());
                 match OutputFormat::from_str(&fmt) {
@@ -87,8 +172,65 @@ impl MessageToggles{
     } 
 }
 
-pub struct Osc {
+/// Collects the messages of one update into bundles, timetagged with the time they apply to
+struct Bundler {
+    delay: f64, // keeper.delay_compensation in seconds
+    tick: SystemTime,
+    messages: Vec<OscPacket>,
+    timed: Vec<(f64, OscPacket)>, // Offset in seconds from the tick
+}
+
+// Below a typical Ethernet MTU, so bundles aren't fragmented or dropped by receivers
+const MAX_BUNDLE_SIZE: usize = 1400;
+
+/// Bundles of `content` sharing `timetag`, each at most `MAX_BUNDLE_SIZE` bytes encoded unless a
+/// single message is larger by itself
+pub(crate) fn split_bundle(timetag: OscTime, content: Vec<OscPacket>) -> Vec<OscPacket> {
+    const HEADER_SIZE: usize = 16; // "#bundle" and the timetag
+    let mut bundles = vec![];
+    let mut current = vec![];
+    let mut size = HEADER_SIZE;
+    for packet in content {
+        // Every element is prefixed with its size
+        let packet_size = 4 + encode(&packet).map(|p| p.len()).unwrap_or(0);
+        if !current.is_empty() && size + packet_size > MAX_BUNDLE_SIZE {
+            bundles.push(OscPacket::Bundle(OscBundle { timetag, content: std::mem::take(&mut current) }));
+            size = HEADER_SIZE;
+        }
+        size += packet_size;
+        current.push(packet);
+    }
+    if !current.is_empty() {
+        bundles.push(OscPacket::Bundle(OscBundle { timetag, content: current }));
+    }
+    bundles
+}
+
+/// Wall-clock time `offset` seconds from `time`, or immediately if it can't be represented
+fn timetag(time: SystemTime, offset: f64) -> OscTime {
+    let time = if offset >= 0. {
+        time.checked_add(Duration::from_secs_f64(offset))
+    } else {
+        time.checked_sub(Duration::from_secs_f64(-offset))
+    };
+    time.and_then(|t| OscTime::try_from(t).ok())
+        .unwrap_or(OscTime { seconds: 0, fractional: 1 })
+}
+
+/// Sending to one destination, with its address prefix, templates and bundling.
+/// Also used by the Resolume module.
+pub(crate) struct OscSender {
     socket: UdpSocket,
+    destination: SocketAddr,
+    prefix: String,
+    logger: ScopedLogger,
+    bundler: Option<RefCell<Bundler>>,
+    templates: HashMap<String, AddressTemplate>,
+}
+
+/// One destination with its own rate, toggles and address prefix
+struct OscTarget {
+    sender: OscSender,
     info_sent: bool,
     logger: ScopedLogger,
     message_toggles: MessageToggles,
@@ -96,50 +238,212 @@ pub struct Osc {
     send_period_counter: i32,
     last_beat_master: f32,
     last_beats: Vec<f32>,
+    bpm_master: f32,
+    bpms: Vec<f32>,
+    // Beat counts waiting for the next update that isn't skipped by send_every_nth
+    pending_counts: Vec<(Target, BeatCount)>,
+    // Last track length in beats sent per deck, which only changes with the track
+    total_beats: Vec<(Target, i32)>,
 }
 
 
+impl OscSender {
+    /// Reads `destination`, `prefix`, `bundle` and `map.<key>`, sending from a clone of `socket`
+    pub(crate) fn new(conf: &Config, socket: &UdpSocket, default_destination: &str, logger: ScopedLogger) -> Result<Self, ()> {
+        let socket = match socket.try_clone() {
+            Ok(socket) => socket,
+            Err(e) => {
+                logger.err(&format!("Failed to share source socket: {e}"));
+                return Err(());
+            }
+        };
 
+        // UDP doesn't require an established connection, so only the address has to be valid
+        let destination_str = conf.get_or_default("destination", default_destination.to_string());
+        let destination = match destination_str.to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => addr,
+            Ok(None) => {
+                logger.err(&format!("No address found for OSC receiver {destination_str}"));
+                return Err(());
+            }
+            Err(e) => {
+                logger.err(&format!("Invalid OSC receiver address {destination_str}: {e}"));
+                return Err(());
+            }
+        };
 
-impl Osc {
-    fn send_float(&self, addr: &str, value: f32) {
-        let msg = OscPacket::Message(OscMessage {
-            addr: addr.to_string(),
-            args: vec![rosc::OscType::Float(value)],
-        });
+        let bundler = if conf.get_or_default("bundle", false) {
+            // The keeper outputs positions this far ahead, so that's when they apply
+            let delay: f64 = conf.reduce_to_namespace("keeper").get_or_default("delay_compensation", 0.);
+            Some(RefCell::new(Bundler {
+                delay: delay / 1000.,
+                tick: SystemTime::now(),
+                messages: vec![],
+                timed: vec![],
+            }))
+        } else {
+            None
+        };
+
+        let mut templates = HashMap::new();
+        for (key, spec) in conf.entries_with_prefix("map.") {
+            match AddressTemplate::parse(&spec) {
+                Ok(template) => {
+                    templates.insert(key, template);
+                }
+                Err(e) => logger.err(&format!("Invalid template 'map.{key} {spec}': {e}")),
+            }
+        }
+
+        Ok(OscSender {
+            socket,
+            destination,
+            prefix: conf.get_or_default("prefix", String::new()).trim_end_matches('/').to_string(),
+            logger,
+            bundler,
+            templates,
+        })
+    }
+
+    pub(crate) fn destination(&self) -> SocketAddr {
+        self.destination
+    }
+
+    pub(crate) fn local_addr(&self) -> Option<SocketAddr> {
+        self.socket.local_addr().ok()
+    }
+
+    /// Start collecting the messages of a new update
+    pub(crate) fn begin_update(&self) {
+        if let Some(bundler) = &self.bundler {
+            bundler.borrow_mut().tick = SystemTime::now();
+        }
+    }
+
+    /// Send the bundles collected since `begin_update`
+    pub(crate) fn end_update(&self) {
+        let Some(bundler) = &self.bundler else {
+            return;
+        };
+        let (tick, delay, messages, timed) = {
+            let mut bundler = bundler.borrow_mut();
+            (bundler.tick, bundler.delay, std::mem::take(&mut bundler.messages), std::mem::take(&mut bundler.timed))
+        };
+        for bundle in split_bundle(timetag(tick, delay), messages) {
+            self.send_packet(&bundle);
+        }
+        for (offset, msg) in timed {
+            self.send_packet(&OscPacket::Bundle(OscBundle {
+                timetag: timetag(tick, delay + offset),
+                content: vec![msg],
+            }));
+        }
+    }
+
+    pub(crate) fn set_delay_compensation(&self, ms: f32) {
+        if let Some(bundler) = &self.bundler {
+            bundler.borrow_mut().delay = ms as f64 / 1000.;
+        }
+    }
+
+    fn message(&self, addr: &str, mut args: Vec<rosc::OscType>) -> OscPacket {
+        let addr = self.templated(addr, &mut args).unwrap_or_else(|| addr.to_string());
+        OscPacket::Message(OscMessage {
+            addr: format!("{}{addr}", self.prefix),
+            args,
+        })
+    }
+
+    /// Apply the template matching a default address such as `/master/beat/subdiv/0.25`, if there is one.
+    /// Templates are keyed like the message toggles, `master/beat/subdiv`, with `*` matching the last part.
+    fn templated(&self, addr: &str, args: &mut [rosc::OscType]) -> Option<String> {
+        if self.templates.is_empty() {
+            return None;
+        }
+        let (deck, path) = addr.strip_prefix('/')?.split_once('/')?;
+        let kind = if deck == "master" { "master" } else { "n" };
+        // Subdivision and trigger addresses end in their interval
+        let (path, d) = match path.rsplit_once('/') {
+            Some((base @ ("beat/subdiv" | "beat/trigger"), d)) => (base, d),
+            _ => (path, ""),
+        };
+        let (group, field) = match path.rsplit_once('/') {
+            Some((group, field)) => (format!("{group}/"), field),
+            None => (String::new(), path),
+        };
+        let template = self
+            .templates
+            .get(&format!("{kind}/{path}"))
+            .or_else(|| self.templates.get(&format!("{kind}/{group}*")))?;
+        template.apply(args);
+        Some(
+            template
+                .address
+                .replace("{deck}", deck)
+                .replace("{d}", d)
+                .replace("{field}", field),
+        )
+    }
+
+    pub(crate) fn send_float(&self, addr: &str, value: f32) {
+        let msg = self.message(addr, vec![rosc::OscType::Float(value)]);
         self.send(msg);
     }
 
-    fn send_string(&self, addr: &str, value: &str) {
-        let msg = OscPacket::Message(OscMessage {
-            addr: addr.to_string(),
-            args: vec![rosc::OscType::String(value.to_string())],
-        });
+    pub(crate) fn send_string(&self, addr: &str, value: &str) {
+        let msg = self.message(addr, vec![rosc::OscType::String(value.to_string())]);
         self.send(msg);
     }
 
-    fn send_int(&self, addr: &str, value: i32) {
-        let msg = OscPacket::Message(OscMessage {
-            addr: addr.to_string(),
-            args: vec![rosc::OscType::Int(value)],
-        });
+    pub(crate) fn send_int(&self, addr: &str, value: i32) {
+        let msg = self.message(addr, vec![rosc::OscType::Int(value)]);
+        self.send(msg);
+    }
+
+    fn send_color(&self, addr: &str, (r, g, b): (u8, u8, u8)) {
+        let msg = self.message(addr, vec![rosc::OscType::Int(r as i32), rosc::OscType::Int(g as i32), rosc::OscType::Int(b as i32)]);
         self.send(msg);
     }
 
+    /// Send a trigger that happened `beats_ago` beats before this update. Bundled, it is
+    /// timetagged with the moment the beat was crossed rather than the update.
+    fn send_trigger(&self, addr: &str, value: f32, beats_ago: f32, bpm: f32) {
+        let msg = self.message(addr, vec![rosc::OscType::Float(value)]);
+        match &self.bundler {
+            Some(bundler) if bpm > 0. => {
+                let offset = -(beats_ago * 60. / bpm) as f64;
+                bundler.borrow_mut().timed.push((offset, msg));
+            }
+            _ => self.send(msg),
+        }
+    }
+
     fn send(&self, msg: OscPacket) {
-        let packet = match encode(&msg){
+        if let Some(bundler) = &self.bundler {
+            bundler.borrow_mut().messages.push(msg);
+            return;
+        }
+        self.send_packet(&msg);
+    }
+
+    fn send_packet(&self, msg: &OscPacket) {
+        let packet = match encode(msg){
             Ok(packet) => packet,
             Err(e) => {
                 self.logger.err(&format!("Failed to encode OSC message: {e}"));
                 return;
             }
         };
-        if let Err(e) = self.socket.send(&packet) {
+        if let Err(e) = self.socket.send_to(&packet, self.destination) {
             self.logger.err(&format!("Failed to send OSC message: {e}"));
         };
     }
 }
 
+pub struct Osc {
+    targets: Vec<OscTarget>,
+}
+
 impl Osc {
     pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
         let socket =
@@ -151,53 +455,197 @@ impl Osc {
                 }
             };
 
-        if let Err(e) =
-            socket.connect(conf.get_or_default("destination", "127.0.0.1:9999".to_string()))
-        {
-            logger.err(&format!("Failed to open connection to receiver: {e}"));
-            return Err(());
+        // Without named targets, the osc.* keys configure a single target
+        let names: Vec<String> = conf
+            .get_or_default("targets", String::new())
+            .split(',')
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect();
+
+        let mut targets = vec![];
+        if names.is_empty() {
+            targets.push(OscTarget::new(&conf, &socket, logger)?);
+        } else {
+            for name in names {
+                let target_logger = ScopedLogger::new(&logger.logger, &format!("OSC {name}"));
+                match OscTarget::new(&conf.sub_namespace_with_fallback(&name), &socket, target_logger) {
+                    Ok(target) => targets.push(target),
+                    Err(()) => logger.err(&format!("Failed to set up target {name}")),
+                }
+            }
+            if targets.is_empty() {
+                return Err(());
+            }
         }
-        // Try to connect to destination, but don't fail if receiver isn't ready yet
-        // UDP doesn't require an established connection to send
-        let destination = conf.get_or_default("destination", "127.0.0.1:9999".to_string());
-        if let Err(e) = socket.connect(&destination) {
-            logger.warn(&format!("Could not open UDP socket to OSC receiver at {}: {}", destination, e));
-            logger.info("OSC will continue attempting to send messages");
+
+        Ok(Box::new(Osc { targets }))
+    }
+}
+
+impl OutputModule for Osc {
+    fn pre_update(&mut self) {
+        for target in &mut self.targets {
+            target.pre_update();
         }
+    }
 
-        Ok(Box::new(Osc {
-            socket,
+    fn post_update(&mut self) {
+        for target in &mut self.targets {
+            target.post_update();
+        }
+    }
+
+    fn on_event(&mut self, target: Target, event: &Event) {
+        for osc_target in &mut self.targets {
+            osc_target.on_event(target, event);
+        }
+    }
+
+    fn slow_update(&mut self) {
+        for target in &mut self.targets {
+            target.slow_update();
+        }
+    }
+
+    fn delay_compensation_changed(&mut self, ms: f32) {
+        for target in &mut self.targets {
+            target.sender.set_delay_compensation(ms);
+        }
+    }
+}
+
+impl OscTarget {
+    fn new(conf: &Config, socket: &UdpSocket, logger: ScopedLogger) -> Result<Self, ()> {
+        let sender = OscSender::new(conf, socket, "127.0.0.1:9999", logger.clone())?;
+
+        Ok(OscTarget {
+            sender,
             info_sent: false,
             logger: logger.clone(),
-            message_toggles: MessageToggles::new(&conf, logger),
-            send_period: conf.get_or_default("send_every_nth", 2),
+            message_toggles: MessageToggles::new(conf, logger),
+            send_period: conf.get_or_default("send_every_nth", 2i32).max(1),
             send_period_counter: 0,
             last_beat_master: 0.0,
             last_beats: vec![0.0; 4],
-        }))
+            bpm_master: 0.0,
+            bpms: vec![0.0; 4],
+            pending_counts: vec![],
+            total_beats: vec![],
+        })
     }
 }
 
 // TODO: Avoid formatting strings every loop
-impl OutputModule for Osc {
+// Driven by `Osc`, each target only handles what is sent to it
+impl OscTarget {
     fn pre_update(&mut self) {
         self.send_period_counter = (self.send_period_counter + 1) % self.send_period;
+        self.sender.begin_update();
+        if self.send_period_counter == 0 {
+            for (target, count) in std::mem::take(&mut self.pending_counts) {
+                self.output_beat_count(target, &count);
+            }
+        }
+    }
+
+    fn post_update(&mut self) {
+        self.sender.end_update();
+    }
+
+    fn on_event(&mut self, target: Target, event: &Event) {
+        match event {
+            Event::PlayState(state) => self.play_state_changed(target, *state),
+            Event::BeatCount(count) => self.beat_count_changed(target, count),
+            // Only changes with the track, so it isn't throttled like the other progress messages
+            Event::TrackLength(length) if self.progress_toggle(target) => {
+                self.sender.send_float(&format!("/{target}/track/length"), *length);
+            }
+            Event::TimeRemaining(remaining) if self.progress_enabled(target) => {
+                self.sender.send_float(&format!("/{target}/time/remaining"), *remaining);
+            }
+            Event::Progress(progress) if self.progress_enabled(target) => {
+                self.sender.send_float(&format!("/{target}/progress"), *progress);
+            }
+            Event::NextCue(cue) if self.cue_enabled(target) => {
+                self.output_cue(&format!("/{target}/cue/next"), cue.as_ref());
+            }
+            Event::NextCueIn(beats) if self.cue_enabled(target) => {
+                self.sender.send_float(&format!("/{target}/cue/countin"), *beats as f32);
+            }
+            Event::CuePassed(cue) if self.cue_enabled(target) => {
+                self.output_cue(&format!("/{target}/cue/passed"), Some(cue));
+            }
+            Event::Energy(energy) => {
+                let enabled = match target {
+                    Target::Master => self.message_toggles.energy_master,
+                    Target::Deck(_) => self.message_toggles.energy,
+                };
+                if enabled && self.send_period_counter == 0 {
+                    self.sender.send_float(&format!("/{target}/energy"), *energy);
+                }
+            }
+            Event::Bands(bands) => {
+                let enabled = match target {
+                    Target::Master => self.message_toggles.bands_master,
+                    Target::Deck(_) => self.message_toggles.bands,
+                };
+                if enabled && self.send_period_counter == 0 {
+                    self.output_bands(target, bands);
+                }
+            }
+            Event::Bpm(bpm) => match target {
+                Target::Master => self.bpm_changed_master(*bpm),
+                Target::Deck(deck) => self.bpm_changed(*bpm, deck),
+            },
+            Event::OriginalBpm(bpm) => match target {
+                Target::Master => self.original_bpm_changed_master(*bpm),
+                Target::Deck(deck) => self.original_bpm_changed(*bpm, deck),
+            },
+            Event::Beat(beat) => match target {
+                Target::Master => self.beat_update_master(*beat),
+                Target::Deck(deck) => self.beat_update(*beat, deck),
+            },
+            Event::Time(time) => match target {
+                Target::Master => self.time_update_master(*time),
+                Target::Deck(deck) => self.time_update(*time, deck),
+            },
+            Event::Track(track) => match target {
+                Target::Master => self.track_changed_master(track),
+                Target::Deck(deck) => self.track_changed(track, deck),
+            },
+            Event::Phrase(phrase) => match target {
+                Target::Master => self.phrase_changed_master(phrase),
+                Target::Deck(deck) => self.phrase_changed(phrase, deck),
+            },
+            Event::NextPhrase(phrase) => match target {
+                Target::Master => self.next_phrase_changed_master(phrase),
+                Target::Deck(deck) => self.next_phrase_changed(phrase, deck),
+            },
+            Event::NextPhraseIn(beats) => match target {
+                Target::Master => self.next_phrase_in_master(*beats),
+                Target::Deck(deck) => self.next_phrase_in(*beats, deck),
+            },
+            _ => (),
+        }
     }
 
     fn bpm_changed_master(&mut self, bpm: f32) {
-        self.send_float("/master/bpm/current", bpm);
+        self.bpm_master = bpm;
+        self.sender.send_float("/master/bpm/current", bpm);
     }
 
     fn bpm_changed(&mut self, bpm: f32, deck: usize) {
-        self.send_float(&format!("/{deck}/bpm/current"), bpm);
+        self.bpms[deck] = bpm;
+        self.sender.send_float(&format!("/{deck}/bpm/current"), bpm);
     }
 
     fn original_bpm_changed_master(&mut self, bpm: f32) {
-        self.send_float("/master/bpm/original", bpm);
+        self.sender.send_float("/master/bpm/original", bpm);
     }
 
     fn original_bpm_changed(&mut self, bpm: f32, deck: usize) {
-        self.send_float(&format!("/{deck}/bpm/original"), bpm);
+        self.sender.send_float(&format!("/{deck}/bpm/original"), bpm);
     }
 
     fn beat_update_master(&mut self, beat: f32) {
@@ -207,14 +655,14 @@ impl OutputModule for Osc {
 
         for d in &self.message_toggles.beat_master_subdivs{
             let value = (beat % d) / d;
-            self.send_float(&format!("/master/beat/subdiv/{d}"), value);
+            self.sender.send_float(&format!("/master/beat/subdiv/{d}"), value);
         }
 
         for d in &self.message_toggles.beat_master_triggers{
             if beat % d < self.last_beat_master % d {
-                self.send_float(&format!("/master/beat/trigger/{d}"), 1.);
+                self.sender.send_trigger(&format!("/master/beat/trigger/{d}"), 1., beat % d, self.bpm_master);
             }else if self.message_toggles.beat_trigger_autorelease && (beat + d * 0.2) % d < (self.last_beat_master + d * 0.2) % d{
-                self.send_float(&format!("/master/beat/trigger/{d}"), 0.);
+                self.sender.send_trigger(&format!("/master/beat/trigger/{d}"), 0., (beat + d * 0.2) % d, self.bpm_master);
             }
         }
         
@@ -227,7 +675,7 @@ impl OutputModule for Osc {
             return;
         }
         if self.message_toggles.time_master{
-            self.send_float("/master/time", time);
+            self.sender.send_float("/master/time", time);
         }
     }
 
@@ -238,15 +686,15 @@ impl OutputModule for Osc {
 
         for d in &self.message_toggles.beat_subdivs{
             let value = (beat % d) / d;
-            self.send_float(&format!("/{deck}/beat/subdiv/{d}"), value);
+            self.sender.send_float(&format!("/{deck}/beat/subdiv/{d}"), value);
         }
 
 
         for d in &self.message_toggles.beat_triggers{
             if beat % d < self.last_beats[deck] % d {
-                self.send_float(&format!("/{deck}/beat/trigger/{d}"), 1.);
+                self.sender.send_trigger(&format!("/{deck}/beat/trigger/{d}"), 1., beat % d, self.bpms[deck]);
             }else if self.message_toggles.beat_trigger_autorelease && (beat + d * 0.2) % d < (self.last_beats[deck] + d * 0.2) % d{
-                self.send_float(&format!("/{deck}/beat/trigger/{d}"), 0.);
+                self.sender.send_trigger(&format!("/{deck}/beat/trigger/{d}"), 0., (beat + d * 0.2) % d, self.bpms[deck]);
             }
         }
         self.last_beats[deck] = beat;
@@ -257,33 +705,25 @@ impl OutputModule for Osc {
             return;
         }
         if self.message_toggles.time{
-            self.send_float(&format!("/{deck}/time"), time);
+            self.sender.send_float(&format!("/{deck}/time"), time);
         }
     }
 
     fn track_changed(&mut self, track: &TrackInfo, deck: usize) {
-        self.send_string(&format!("/{deck}/track/title"), &track.title);
-        self.send_string(&format!("/{deck}/track/artist"), &track.artist);
-        self.send_string(&format!("/{deck}/track/album"), &track.album);
+        self.output_track(&deck.to_string(), track);
     }
 
     fn track_changed_master(&mut self, track: &TrackInfo) {
-        self.send_string("/master/track/title", &track.title);
-        self.send_string("/master/track/artist", &track.artist);
-        self.send_string("/master/track/album", &track.album);
+        self.output_track("master", track);
     }
 
     fn slow_update(&mut self) {
         if !self.info_sent {
             self.info_sent = true;
 
-            let target_addr = if let Ok(addr) = self.socket.peer_addr() {
-                addr.to_string()
-            } else {
-                "No target!!".to_string()
-            };
+            let target_addr = self.sender.destination();
 
-            let source_addr = if let Ok(addr) = self.socket.local_addr() {
+            let source_addr = if let Some(addr) = self.sender.local_addr() {
                 addr.to_string()
             } else {
                 "No source!!".to_string()
@@ -307,7 +747,7 @@ impl OutputModule for Osc {
 
     fn next_phrase_in_master(&mut self, beats: i32) {
         if self.message_toggles.phrase_master{
-            self.send_float("/master/phrase/countin", beats as f32);
+            self.sender.send_float("/master/phrase/countin", beats as f32);
         }
     }
 
@@ -325,17 +765,209 @@ impl OutputModule for Osc {
 
     fn next_phrase_in(&mut self, beats: i32, deck: usize) {
         if self.message_toggles.phrase{
-            self.send_float(&format!("/{deck}/phrase/countin"), beats as f32);
+            self.sender.send_float(&format!("/{deck}/phrase/countin"), beats as f32);
         }
     }
 }
 
-impl Osc{
+impl OscTarget{
+    fn play_state_changed(&mut self, target: Target, state: PlayState) {
+        let enabled = match target {
+            Target::Master => self.message_toggles.transport_master,
+            Target::Deck(_) => self.message_toggles.transport,
+        };
+        if !enabled {
+            return;
+        }
+        self.sender.send_float(&format!("/{target}/transport/playing"), if state.is_playing() { 1. } else { 0. });
+        self.sender.send_int(&format!("/{target}/transport/state"), state.to_index() as i32);
+    }
+
+    fn beat_count_changed(&mut self, target: Target, count: &BeatCount) {
+        let enabled = match target {
+            Target::Master => self.message_toggles.beat_count_master,
+            Target::Deck(_) => self.message_toggles.beat_count,
+        };
+        if !enabled {
+            return;
+        }
+        // Counts only change once per beat, so a skipped update sends them on the next one instead
+        self.pending_counts.retain(|(t, _)| *t != target);
+        if self.send_period_counter == 0 {
+            self.output_beat_count(target, count);
+        } else {
+            self.pending_counts.push((target, *count));
+        }
+    }
+
+    fn output_beat_count(&mut self, target: Target, count: &BeatCount) {
+        self.sender.send_int(&format!("/{target}/beat/number"), count.beat);
+        self.sender.send_int(&format!("/{target}/beat/bar"), count.bar);
+        self.sender.send_int(&format!("/{target}/beat/in_bar"), count.beat_in_bar);
+        if !self.total_beats.contains(&(target, count.total_beats)) {
+            self.total_beats.retain(|(t, _)| *t != target);
+            self.total_beats.push((target, count.total_beats));
+            self.sender.send_int(&format!("/{target}/beat/total"), count.total_beats);
+        }
+    }
+
+    fn progress_enabled(&self, target: Target) -> bool {
+        self.send_period_counter == 0 && self.progress_toggle(target)
+    }
+
+    fn progress_toggle(&self, target: Target) -> bool {
+        match target {
+            Target::Master => self.message_toggles.progress_master,
+            Target::Deck(_) => self.message_toggles.progress,
+        }
+    }
+
+    fn cue_enabled(&self, target: Target) -> bool {
+        match target {
+            Target::Master => self.message_toggles.cue_master,
+            Target::Deck(_) => self.message_toggles.cue,
+        }
+    }
+
+    fn output_cue(&mut self, addr: &str, cue: Option<&Cue>) {
+        let Some(cue) = cue else {
+            self.sender.send_string(addr, "");
+            return;
+        };
+        self.sender.send_string(addr, &cue.name());
+        self.sender.send_string(&format!("{addr}/comment"), &cue.comment);
+        if let Some(color) = cue.color {
+            self.sender.send_color(&format!("{addr}/color"), color);
+        }
+    }
+
+    fn output_track(&mut self, deck: &str, track: &TrackInfo) {
+        self.sender.send_string(&format!("/{deck}/track/title"), &track.title);
+        self.sender.send_string(&format!("/{deck}/track/artist"), &track.artist);
+        self.sender.send_string(&format!("/{deck}/track/album"), &track.album);
+        self.sender.send_string(&format!("/{deck}/track/key"), &track.key);
+        self.sender.send_string(&format!("/{deck}/track/genre"), &track.genre);
+        self.sender.send_string(&format!("/{deck}/track/label"), &track.label);
+        self.sender.send_int(&format!("/{deck}/track/rating"), track.rating as i32);
+        self.sender.send_string(&format!("/{deck}/track/comment"), &track.comment);
+    }
+
+    fn output_bands(&mut self, target: Target, bands: &Bands) {
+        self.sender.send_float(&format!("/{target}/energy/low"), bands.low);
+        self.sender.send_float(&format!("/{target}/energy/mid"), bands.mid);
+        self.sender.send_float(&format!("/{target}/energy/high"), bands.high);
+    }
+
     fn output_phrase(&mut self, addr: &str, phrase: &str){
         match self.message_toggles.phrase_output_format {
-            OutputFormat::String => self.send_string(addr, phrase),
-            OutputFormat::Int => self.send_int(addr, PhraseParser::phrase_name_to_index(phrase)),
-            OutputFormat::Float => self.send_float(addr, PhraseParser::phrase_name_to_index(phrase) as f32),
+            OutputFormat::String => self.sender.send_string(addr, phrase),
+            OutputFormat::Int => self.sender.send_int(addr, PhraseParser::phrase_name_to_index(phrase)),
+            OutputFormat::Float => self.sender.send_float(addr, PhraseParser::phrase_name_to_index(phrase) as f32),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(addr: &str) -> OscPacket {
+        OscPacket::Message(OscMessage { addr: addr.to_string(), args: vec![rosc::OscType::Float(1.)] })
+    }
+
+    #[test]
+    fn splits_bundles_by_size() {
+        let time = OscTime { seconds: 1, fractional: 0 };
+        // 24 bytes encoded plus the size prefix
+        let content: Vec<OscPacket> = (0..100).map(|i| message(&format!("/deck/{i:04}"))).collect();
+        let bundles = split_bundle(time, content.clone());
+        assert!(bundles.len() > 1);
+
+        let mut messages = vec![];
+        for bundle in bundles {
+            assert!(encode(&bundle).unwrap().len() <= MAX_BUNDLE_SIZE);
+            let OscPacket::Bundle(bundle) = bundle else { panic!("expected a bundle") };
+            assert_eq!(bundle.timetag, time);
+            messages.extend(bundle.content);
+        }
+        assert_eq!(messages, content);
+    }
+
+    #[test]
+    fn keeps_oversized_messages() {
+        let time = OscTime { seconds: 1, fractional: 0 };
+        let long = message(&"/a".repeat(MAX_BUNDLE_SIZE));
+        let bundles = split_bundle(time, vec![message("/b"), long, message("/c")]);
+        assert_eq!(bundles.len(), 3);
+        assert!(split_bundle(time, vec![]).is_empty());
+    }
+
+    #[test]
+    fn target_templates_fall_back_to_osc_map() {
+        use std::rc::Rc;
+        use crate::log::Logger;
+
+        let logger = ScopedLogger::new(&Rc::new(Logger::new(false)), "OSC");
+        let conf = Config::parse(
+            "osc.map.master/bpm /all/bpm\nosc.map.master/time /all/time\nosc.stage.map.master/bpm /stage/bpm\nosc.stage.map.master/beat /stage/beat",
+            logger.clone(),
+        )
+        .reduce_to_namespace("osc");
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let sender = OscSender::new(&conf.sub_namespace_with_fallback("stage"), &socket, "127.0.0.1:9999", logger.clone()).unwrap();
+        let mut addresses: Vec<(&str, &str)> = sender.templates.iter().map(|(k, t)| (k.as_str(), t.address.as_str())).collect();
+        addresses.sort();
+        assert_eq!(addresses, [("master/beat", "/stage/beat"), ("master/bpm", "/stage/bpm"), ("master/time", "/all/time")]);
+
+        let sender = OscSender::new(&conf.sub_namespace_with_fallback("booth"), &socket, "127.0.0.1:9999", logger).unwrap();
+        assert_eq!(sender.templates.len(), 2);
+        assert_eq!(sender.templates["master/bpm"].address, "/all/bpm");
+    }
+
+    #[test]
+    fn parses_address_templates() {
+        let template = AddressTemplate::parse("/lights/tempo").unwrap();
+        assert_eq!(template.address, "/lights/tempo");
+        assert!(template.scale.is_none() && template.format.is_none());
+
+        let template = AddressTemplate::parse("/fader scale=60:180 type=int").unwrap();
+        assert_eq!(template.scale, Some((60., 180., 0., 1.)));
+        assert!(matches!(template.format, Some(OutputFormat::Int)));
+
+        let template = AddressTemplate::parse("/fader scale=0:1:0:127").unwrap();
+        assert_eq!(template.scale, Some((0., 1., 0., 127.)));
+
+        assert!(AddressTemplate::parse("").is_err());
+        assert!(AddressTemplate::parse("/fader scale=1:1").is_err());
+        assert!(AddressTemplate::parse("/fader scale=0:1:2").is_err());
+        assert!(AddressTemplate::parse("/fader type=double").is_err());
+        assert!(AddressTemplate::parse("/fader offset=1").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_scales() {
+        assert!(AddressTemplate::parse("/fader scale=0..x").is_err());
+        assert!(AddressTemplate::parse("/fader scale=0:x").is_err());
+        assert!(AddressTemplate::parse("/fader scale=0:1:a:127").is_err());
+        assert!(AddressTemplate::parse("/fader scale=0:1:").is_err());
+        assert!(AddressTemplate::parse("/fader scale=0:inf").is_err());
+    }
+
+    #[test]
+    fn applies_address_templates() {
+        use rosc::OscType;
+
+        let mut args = vec![OscType::Float(120.), OscType::Int(300), OscType::String("intro".to_string())];
+        AddressTemplate::parse("/fader scale=60:180").unwrap().apply(&mut args);
+        assert_eq!(args, vec![OscType::Float(0.5), OscType::Int(1), OscType::String("intro".to_string())]);
+
+        let mut args = vec![OscType::Float(0.5)];
+        AddressTemplate::parse("/fader scale=0:1:0:127 type=int").unwrap().apply(&mut args);
+        assert_eq!(args, vec![OscType::Int(64)]);
+
+        let mut args = vec![OscType::Int(4)];
+        AddressTemplate::parse("/label type=string").unwrap().apply(&mut args);
+        assert_eq!(args, vec![OscType::String("4".to_string())]);
+    }
+}
@@ -0,0 +1,234 @@
+use std::net::UdpSocket;
+
+use crate::{beatkeeper::TrackInfo, config::Config, events::{BeatCount, Event, Target}, log::ScopedLogger, utils::PhraseParser};
+
+use super::osc::OscSender;
+use super::{dispatch_legacy, ModuleCreateOutput, OutputModule};
+
+// Resolume's tempo parameter is normalised over this range
+const MIN_BPM: f32 = 20.;
+const MAX_BPM: f32 = 500.;
+
+#[derive(PartialEq)]
+enum ResyncOn {
+    Never,
+    Bar,
+    Phrase,
+}
+
+/// What to connect when a phrase starts
+enum PhraseTrigger {
+    Column(u32),
+    Clip(u32, u32),
+}
+
+impl PhraseTrigger {
+    /// Parse `column:<column>` or `clip:<layer>:<clip>`
+    fn parse(s: &str) -> Option<Self> {
+        let parts: Vec<&str> = s.split(':').map(|x| x.trim()).collect();
+        match parts.as_slice() {
+            ["column", column] => Some(PhraseTrigger::Column(column.parse().ok()?)),
+            ["clip", layer, clip] => Some(PhraseTrigger::Clip(layer.parse().ok()?, clip.parse().ok()?)),
+            _ => None,
+        }
+    }
+
+    fn address(&self) -> String {
+        match self {
+            PhraseTrigger::Column(column) => format!("/composition/columns/{column}/connect"),
+            PhraseTrigger::Clip(layer, clip) => format!("/composition/layers/{layer}/clips/{clip}/connect"),
+        }
+    }
+}
+
+/// Drives the Resolume composition tempo from the master deck, connects columns or clips on phrase
+/// changes and writes the track info into a text source
+pub struct Resolume {
+    sender: OscSender,
+    tempo: bool,
+    tap: bool,
+    resync_on: ResyncOn,
+    phrase_triggers: Vec<(i32, PhraseTrigger)>,
+    text_address: String,
+    text_fields: Vec<String>,
+    playing: bool,
+    last_count: Option<BeatCount>,
+    last_phrase: i32,
+}
+
+impl Resolume {
+    pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
+        let socket = match UdpSocket::bind(conf.get_or_default("source", "0.0.0.0:0".to_string())) {
+            Ok(socket) => socket,
+            Err(e) => {
+                logger.err(&format!("Failed to open source socket: {e}"));
+                return Err(());
+            }
+        };
+        // Same destination, prefix, bundle and map keys as an OSC target
+        let sender = OscSender::new(&conf, &socket, "127.0.0.1:7000", logger.clone())?;
+
+        let tempo = conf.get_or_default("tempo", true);
+        let mut tap = conf.get_or_default("tap", false);
+        if tempo && tap {
+            // Taps would fight the tempo that is set directly
+            logger.warn("tap and tempo both set the tempo, only using tempo. Set resolume.tempo false to tap instead");
+            tap = false;
+        }
+
+        let resync_on = match conf.get_or_default("resync_on", "bar".to_string()).as_str() {
+            "bar" => ResyncOn::Bar,
+            "phrase" => ResyncOn::Phrase,
+            "never" => ResyncOn::Never,
+            other => {
+                logger.err(&format!("Unknown resync_on '{other}', using bar"));
+                ResyncOn::Bar
+            }
+        };
+
+        // phrase.<index> column:<column> or clip:<layer>:<clip>, with the indices of PhraseParser
+        let mut phrase_triggers = vec![];
+        for (index, trigger) in conf.entries_with_prefix("phrase.") {
+            if trigger.trim().is_empty() {
+                continue;
+            }
+            match (index.parse::<i32>(), PhraseTrigger::parse(&trigger)) {
+                (Ok(index), Some(trigger)) => phrase_triggers.push((index, trigger)),
+                _ => logger.err(&format!(
+                    "Invalid phrase trigger 'phrase.{index} {trigger}', expected phrase.<index> column:<column> or clip:<layer>:<clip>"
+                )),
+            }
+        }
+
+        let text_fields = conf
+            .get_or_default("text_fields", "title,artist".to_string())
+            .split(',')
+            .map(|x| x.trim().to_string())
+            .filter(|x| {
+                let valid = TrackInfo::FIELDS.contains(&x.as_str());
+                if !valid && !x.is_empty() {
+                    logger.err(&format!("Unknown track field '{x}'"));
+                }
+                valid
+            })
+            .collect();
+
+        logger.info(&format!("Sending to Resolume at {}", sender.destination()));
+
+        Ok(Box::new(Resolume {
+            sender,
+            tempo,
+            tap,
+            resync_on,
+            phrase_triggers,
+            text_address: conf.get_or_default(
+                "text_address",
+                "/composition/layers/1/clips/1/video/source/blocktextgenerator/text/params/lines".to_string(),
+            ),
+            text_fields,
+            playing: false,
+            last_count: None,
+            last_phrase: 0,
+        }))
+    }
+
+    /// Buttons in Resolume fire on 1
+    fn press(&self, addr: &str) {
+        self.sender.send_int(addr, 1);
+    }
+
+    fn beat_count_changed(&mut self, count: &BeatCount) {
+        let Some(last) = self.last_count.replace(*count) else {
+            return;
+        };
+        // Counts also change when scrubbing or loading a track, which shouldn't tap or resync
+        if !self.playing {
+            return;
+        }
+        if self.tap && count.beat != last.beat {
+            self.press("/composition/tempocontroller/tempotap");
+        }
+        if self.resync_on == ResyncOn::Bar && count.bar != last.bar {
+            self.press("/composition/tempocontroller/resync");
+        }
+    }
+}
+
+impl OutputModule for Resolume {
+    fn pre_update(&mut self) {
+        self.sender.begin_update();
+    }
+
+    fn post_update(&mut self) {
+        self.sender.end_update();
+    }
+
+    fn delay_compensation_changed(&mut self, ms: f32) {
+        self.sender.set_delay_compensation(ms);
+    }
+
+    fn on_event(&mut self, target: Target, event: &Event) {
+        match (target, event) {
+            (Target::Master, Event::PlayState(state)) => self.playing = state.is_playing(),
+            (Target::Master, Event::BeatCount(count)) => self.beat_count_changed(count),
+            _ => dispatch_legacy(self, target, event),
+        }
+    }
+
+    fn bpm_changed_master(&mut self, bpm: f32) {
+        if self.tempo {
+            let value = ((bpm - MIN_BPM) / (MAX_BPM - MIN_BPM)).clamp(0., 1.);
+            self.sender.send_float("/composition/tempocontroller/tempo", value);
+        }
+    }
+
+    fn phrase_changed_master(&mut self, phrase: &str) {
+        if self.resync_on == ResyncOn::Phrase {
+            self.press("/composition/tempocontroller/resync");
+        }
+
+        // Phrases like Verse 1 and Verse 2 share an index, keep the column playing between them
+        let index = PhraseParser::phrase_name_to_index(phrase);
+        if index == self.last_phrase {
+            return;
+        }
+        self.last_phrase = index;
+        for (_, trigger) in self.phrase_triggers.iter().filter(|(i, _)| *i == index) {
+            self.press(&trigger.address());
+        }
+    }
+
+    fn track_changed_master(&mut self, track: &TrackInfo) {
+        if self.text_address.is_empty() {
+            return;
+        }
+        let lines: Vec<String> = self.text_fields.iter().filter_map(|x| track.field(x)).collect();
+        self.sender.send_string(&self.text_address, &lines.join("\n"));
+    }
+
+    fn resync(&mut self) {
+        self.press("/composition/tempocontroller/resync");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_phrase_triggers() {
+        let trigger = PhraseTrigger::parse("column:3").unwrap();
+        assert!(matches!(trigger, PhraseTrigger::Column(3)));
+        assert_eq!(trigger.address(), "/composition/columns/3/connect");
+
+        let trigger = PhraseTrigger::parse("clip: 2 : 5").unwrap();
+        assert!(matches!(trigger, PhraseTrigger::Clip(2, 5)));
+        assert_eq!(trigger.address(), "/composition/layers/2/clips/5/connect");
+
+        assert!(PhraseTrigger::parse("").is_none());
+        assert!(PhraseTrigger::parse("column").is_none());
+        assert!(PhraseTrigger::parse("column:x").is_none());
+        assert!(PhraseTrigger::parse("clip:2").is_none());
+        assert!(PhraseTrigger::parse("layer:1:2").is_none());
+    }
+}
@@ -1,9 +1,11 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
 
 use sacn::packet::ACN_SDT_MULTICAST_PORT;
 use sacn::source::SacnSource;
+use uuid::Uuid;
 
-use crate::{config::Config, log::ScopedLogger};
+use crate::{config::Config, events::{Event, Target}, log::ScopedLogger};
+use super::dmx::DmxSlots;
 use super::ModuleCreateOutput;
 use super::OutputModule;
 
@@ -12,28 +14,39 @@ use super::OutputModule;
 /// Config keys (with defaults):
 /// - `source` (String): local bind address, e.g. "0.0.0.0:5569". Default: bind to 0.0.0.0 on ACN port+1 (5569).
 /// - `mode` (String): "multicast" (default) or "unicast".
-/// - `universe` (u16): sACN universe (1..=63999), default 1.
-/// - `start_channel` (u16): DMX start/offset (1..=511), default 1. (We need 2 slots: beat count and BPM.)
+/// - `universe` (u16): sACN universe (1..=63999) of the master channels, default 1.
+/// - `deck<n>.universe` (u16): adds a block of channels for deck n (0-based) in this universe,
+///   configured with `deck<n>.start_channel`, `deck<n>.layout` and `deck<n>.map.<channel>`. It may share the universe
+///   with other blocks as long as the channels don't overlap.
 /// - `targets` (String): comma-separated IPv4 list for unicast. Example: "192.168.0.50,192.168.0.51".
 /// - `priority` (u8): sACN priority 1..200, default 100.
 /// - `source_name` (String): up to 63 ASCII chars shown by receivers. Default: "rkbx_link".
 ///
-/// Slot mapping (starting at `start_channel`):
-/// - +0 : BPM (u8). Capped to 250. Values > 250 are sent as 250.
-/// - +1 : Beat absolute counter (u8). Wraps 0..=255.
+/// See `DmxSlots` for `start_channel`, `layout`, `map.<channel>` and the channel layout.
+///
+/// Sequence numbers are kept per universe by `SacnSource`. Streams are terminated when the module is dropped,
+/// which `BeatKeeper::start` does on Ctrl-C. Closing the console window or killing the process skips this,
+/// and receivers fall back to their data loss timeout.
 ///
 pub struct Sacn {
     src: SacnSource,
+    cid: Uuid,
+    source_name: String,
     mode: Mode,
     targets: Vec<SocketAddr>,
-    universe: u16,
-    start_slot: usize, // 1..=511 (we need 2 slots)
+    universes: Vec<u16>,
+    blocks: Vec<Block>,
+    // Universes to send at the end of the update
+    dirty: Vec<u16>,
     priority: u8,
     local_addr: SocketAddr,
-    dmx: [u8; 513], // index 0 is start code = 0, then 512 DMX slots
     logger: ScopedLogger,
-    last_beat_floor: i32,
-    beat_counter: u8,
+}
+
+/// Channels for one target in a universe
+struct Block {
+    universe: u16,
+    slots: DmxSlots,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -71,7 +84,9 @@ impl Sacn
             },
         };
 
-        let mut src = match SacnSource::with_ip(&source_name, local_addr) {
+        // Kept to send unicast stream terminations as the same source
+        let cid = Uuid::new_v4();
+        let mut src = match SacnSource::with_cid_ip(&source_name, cid, local_addr) {
             Ok(src) => src,
             Err(e) => {
                 logger.err(&format!("Failed to create SacnSource: {}", e));
@@ -90,26 +105,26 @@ impl Sacn
             }
         };
 
-        // Universe
-        let mut universe: u16 = conf.get_or_default("universe", 1u16);
-        if universe == 0 {
-            logger.warn("Universe 0 is invalid, using 1");
-            universe = 1;
-        }
-        if let Err(e) = src.register_universe(universe) {
-            logger.err(&format!("register_universe failed: {}", e));
-            return Err(());
+        // Master block, then a block for every deck with a universe set
+        let mut blocks = vec![Self::create_block(&conf, &logger, Target::Master)];
+        for (key, _) in conf.entries_with_prefix("deck") {
+            let Some(deck) = key.strip_suffix(".universe") else {
+                continue;
+            };
+            match deck.parse::<usize>() {
+                Ok(deck) => blocks.push(Self::create_block(&conf.sub_namespace(&format!("deck{deck}")), &logger, Target::Deck(deck))),
+                Err(_) => logger.err(&format!("Invalid deck '{deck}' in deck{deck}.universe")),
+            }
         }
 
-        // Start slot (1-511 so we have 2 slots available)
-        let mut start_slot: usize = conf.get_or_default("start_channel", 1u16) as usize;
-        if start_slot < 1 {
-            logger.warn("start_channel < 1 invalid, using 1");
-            start_slot = 1;
-        }
-        if start_slot > 511 {
-            logger.warn("start_channel > 511 invalid, using 511");
-            start_slot = 511;
+        let mut universes: Vec<u16> = blocks.iter().map(|b| b.universe).collect();
+        universes.sort();
+        universes.dedup();
+        for &universe in &universes {
+            if let Err(e) = src.register_universe(universe) {
+                logger.err(&format!("register_universe failed: {}", e));
+                return Err(());
+            }
         }
 
         // Priority
@@ -137,52 +152,71 @@ impl Sacn
                 }
             }
         }
+        for block in &blocks {
+            logger.info(&format!(
+                "sACN config: target={}, universe={}, channels={:?}",
+                block.slots.target(),
+                block.universe,
+                block.slots.channels()
+            ));
+        }
         logger.info(&format!(
-            "sACN config: priority={}, start_slot={}, universe={}, mode={}, local_addr={}, targets={:?}",
+            "sACN config: priority={}, universes={:?}, mode={}, local_addr={}, targets={:?}",
             priority,
-            start_slot,
-            universe,
+            universes,
             mode_str,
             local_addr,
             targets
         ));
 
-        // DMX buffer (start code + 512 slots)
-        let mut dmx = [0u8; 513];
-        dmx[0] = 0x00; // start code
-
         Ok(Box::new(Sacn {
             src,
+            cid,
+            source_name,
             mode,
             targets,
-            universe,
-            start_slot,
+            universes,
+            blocks,
+            dirty: vec![],
             priority,
             local_addr,
-            dmx,
             logger,
-            last_beat_floor: i32::MIN,
-            beat_counter: 0,
         }))
     }
 
-    fn send(&mut self) {
-        //only send up to the bytes we actually use (using a low start_slot prevents sending the whole universe on update)
-        let last_slot = (self.start_slot + 1).min(512);
-        let len = 1 + last_slot; // +1 for start code
-        let data: &[u8] = &self.dmx[..len];
+    fn create_block(conf: &Config, logger: &ScopedLogger, target: Target) -> Block {
+        let mut universe: u16 = conf.get_or_default("universe", 1u16);
+        if universe == 0 {
+            logger.warn("Universe 0 is invalid, using 1");
+            universe = 1;
+        }
+        Block {
+            universe,
+            slots: DmxSlots::new(conf, logger.clone(), target),
+        }
+    }
+
+    fn send(&mut self, universe: u16) {
+        // Merge every block in the universe, only up to the last channel in use
+        let mut dmx = [0u8; 513];
+        let mut len = 1;
+        for block in self.blocks.iter().filter(|b| b.universe == universe) {
+            block.slots.write_into(&mut dmx);
+            len = len.max(1 + block.slots.channels().end());
+        }
+        let data = &dmx[..len];
 
         match self.mode {
             Mode::Multicast => {
                 let _ = self
                     .src
-                    .send(&[self.universe], data, Some(self.priority), None, None);
+                    .send(&[universe], data, Some(self.priority), None, None);
                 }
             Mode::Unicast => {
                 for &dst in &self.targets {
                     let _ = self
                         .src
-                        .send(&[self.universe], data, Some(self.priority), Some(dst), None);
+                        .send(&[universe], data, Some(self.priority), Some(dst), None);
                     }
             }
         }
@@ -191,52 +225,140 @@ impl Sacn
             Mode::Multicast => {
                 self.logger.debug(&format!(
                     "sending multicast @{} -> universe {} ({} bytes)",
-                    self.local_addr, self.universe, len
+                    self.local_addr, universe, len
                 ));
             }
             Mode::Unicast => {
                 self.logger.debug(&format!(
                     "sending unicast @{} -> {} targets, universe {} ({} bytes)",
-                    self.local_addr, self.targets.len(), self.universe, len
+                    self.local_addr, self.targets.len(), universe, len
                 ));
             }
         }
     }
 
 
-
-    #[inline]
-    fn write_u8_slot(&mut self, slot_1based: usize, value: u8) {
-        // DMX slots live at dmx[1..=512]. slot_1based in 1..=512
-        if (1..=512).contains(&slot_1based) {
-            self.dmx[slot_1based] = value; // +0 because index 0 is start code
-        }
-    }
 }
 
 impl OutputModule for Sacn {
-    fn bpm_changed_master(&mut self, bpm: f32){
-        let mut v = bpm.round() as i32;
-        v = v.clamp(0, 250);
-        self.write_u8_slot(self.start_slot, v as u8); //only send/flush on beat change and slow update to avoid congestion.
-        self.logger.debug(&format!("sACN: BPM changed to {}", v));
+    fn on_event(&mut self, target: Target, event: &Event) {
+        for block in &mut self.blocks {
+            if block.slots.update(target, event) && !self.dirty.contains(&block.universe) {
+                self.dirty.push(block.universe);
+            }
+        }
     }
 
-    fn beat_update_master(&mut self, beat: f32){
-        let floor_now = beat.floor() as i32;
-       
-        if self.last_beat_floor != floor_now {
-            self.last_beat_floor = floor_now;
-            self.beat_counter = self.beat_counter.wrapping_add(1);
-            self.write_u8_slot(self.start_slot + 1, self.beat_counter);
-            self.send();
-            self.logger.debug(&format!("sACN: Beat updated to {}, counter={}", beat, self.beat_counter));
+    /// One packet per changed universe and update, however many of its slots changed
+    fn post_update(&mut self) {
+        for universe in std::mem::take(&mut self.dirty) {
+            self.send(universe);
         }
     }
 
     fn slow_update(&mut self) {
         //this is done as a keepalive.
-        //eventually add some info here like play/pause state, etc.
-        self.send();
+        self.dirty = self.universes.clone();
+    }
+}
+
+impl Sacn {
+    /// `SacnSource` only terminates streams over multicast, so unicast targets are sent the
+    /// terminated packets directly.
+    fn terminate_unicast(&self) {
+        let socket = match UdpSocket::bind(SocketAddr::new(self.local_addr.ip(), 0)) {
+            Ok(socket) => socket,
+            Err(e) => {
+                self.logger.err(&format!("Failed to bind socket for stream termination: {e}"));
+                return;
+            }
+        };
+        for &universe in &self.universes {
+            // The sequence number of the source isn't available. Receivers drop packets up to 20 behind
+            // the last one, so at most one of three evenly spread numbers can be dropped.
+            for sequence in [0, 85, 170] {
+                let packet = termination_packet(self.cid.as_bytes(), &self.source_name, self.priority, universe, sequence);
+                for dst in &self.targets {
+                    if let Err(e) = socket.send_to(&packet, dst) {
+                        self.logger.err(&format!("Failed to terminate universe {universe} on {dst}: {e}"));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// E1.31 data packet with the Stream_Terminated option set and only the start code as data
+fn termination_packet(cid: &[u8; 16], source_name: &str, priority: u8, universe: u16, sequence: u8) -> Vec<u8> {
+    const LENGTH: u16 = 126;
+    let flags_length = |offset: u16| (0x7000 | (LENGTH - offset)).to_be_bytes();
+
+    let mut packet = Vec::with_capacity(LENGTH as usize);
+    // Root layer
+    packet.extend_from_slice(&0x0010u16.to_be_bytes()); // Preamble size
+    packet.extend_from_slice(&0u16.to_be_bytes()); // Postamble size
+    packet.extend_from_slice(b"ASC-E1.17\0\0\0");
+    packet.extend_from_slice(&flags_length(16));
+    packet.extend_from_slice(&4u32.to_be_bytes()); // VECTOR_ROOT_E131_DATA
+    packet.extend_from_slice(cid);
+    // Framing layer
+    packet.extend_from_slice(&flags_length(38));
+    packet.extend_from_slice(&2u32.to_be_bytes()); // VECTOR_E131_DATA_PACKET
+    let mut name = [0u8; 64];
+    let len = source_name.len().min(63);
+    name[..len].copy_from_slice(&source_name.as_bytes()[..len]);
+    packet.extend_from_slice(&name);
+    packet.push(priority);
+    packet.extend_from_slice(&0u16.to_be_bytes()); // Synchronization address
+    packet.push(sequence);
+    packet.push(0x40); // Options: Stream_Terminated
+    packet.extend_from_slice(&universe.to_be_bytes());
+    // DMP layer
+    packet.extend_from_slice(&flags_length(115));
+    packet.push(2); // VECTOR_DMP_SET_PROPERTY
+    packet.push(0xa1); // Address type and data type
+    packet.extend_from_slice(&0u16.to_be_bytes()); // First property address
+    packet.extend_from_slice(&1u16.to_be_bytes()); // Address increment
+    packet.extend_from_slice(&1u16.to_be_bytes()); // Property value count
+    packet.push(0); // Start code
+    packet
+}
+
+impl Drop for Sacn {
+    fn drop(&mut self) {
+        // Tell receivers the stream has ended instead of letting them time out holding the last values
+        match self.mode {
+            Mode::Multicast => {
+                for &universe in &self.universes {
+                    if let Err(e) = self.src.terminate_stream(universe, 0) {
+                        self.logger.err(&format!("Failed to terminate universe {universe}: {e}"));
+                    }
+                }
+            }
+            Mode::Unicast => self.terminate_unicast(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn termination_packet_layout() {
+        let cid = [7u8; 16];
+        let packet = termination_packet(&cid, "rkbx_link", 100, 0x0102, 85);
+        assert_eq!(packet.len(), 126);
+        assert_eq!(&packet[4..16], b"ASC-E1.17\0\0\0");
+        assert_eq!(&packet[16..18], &[0x70, 110]);
+        assert_eq!(&packet[22..38], &cid);
+        assert_eq!(&packet[38..40], &[0x70, 88]);
+        assert_eq!(&packet[44..53], b"rkbx_link");
+        assert_eq!(packet[108], 100);
+        assert_eq!(packet[111], 85);
+        assert_eq!(packet[112], 0x40);
+        assert_eq!(&packet[113..115], &[1, 2]);
+        assert_eq!(&packet[115..117], &[0x70, 11]);
+        assert_eq!(&packet[123..125], &[0, 1]);
     }
 }
@@ -14,6 +14,7 @@ pub struct Setlist {
     stopped: bool,
     filename: String,
     separator: String,
+    extra_fields: Vec<String>,
 }
 
 impl Setlist {
@@ -23,6 +24,12 @@ impl Setlist {
         let mut setlist = Setlist {
             filename,
             separator: config.get_or_default("separator", " - ".to_string()),
+            extra_fields: config
+                .get_or_default("extra_fields", String::new())
+                .split(',')
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty())
+                .collect(),
             stopped: true,
             start_time: 0,
             logger: logger.clone(),
@@ -66,6 +73,12 @@ impl Setlist {
             }
         }
 
+        for field in &setlist.extra_fields {
+            if !TrackInfo::FIELDS.contains(&field.as_str()) {
+                logger.err(&format!("Unknown track field '{field}'"));
+            }
+        }
+
         Ok(Box::new(setlist))
     }
 
@@ -97,13 +110,27 @@ impl OutputModule for Setlist {
         {
             let elapsed_time = self.get_seconds() - self.start_time;
 
+            // Extra fields go in brackets after the title, skipping empty ones
+            let extra: Vec<String> = self
+                .extra_fields
+                .iter()
+                .filter_map(|x| track.field(x))
+                .filter(|x| !x.is_empty())
+                .collect();
+            let extra = if extra.is_empty() {
+                String::new()
+            } else {
+                format!(" [{}]", extra.join(", "))
+            };
+
             writeln!(
                 file,
-                "{} {} {} {}",
+                "{} {} {} {}{}",
                 Self::to_timestamp(elapsed_time),
                 track.artist,
                 self.separator,
-                track.title
+                track.title,
+                extra
             ).unwrap_or_else(|e| {
                 self.logger.err(&format!("Failed to write to setlist file: {e}"));
             });
@@ -0,0 +1,482 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+
+use crate::beatkeeper::TrackInfo;
+use crate::config::Config;
+use crate::cues::Cue;
+use crate::events::{Event, PlayState, Target};
+use crate::log::ScopedLogger;
+
+use super::http::{self, Request};
+use super::{ModuleCreateOutput, OutputModule};
+
+// Appended to the client key in the handshake, from RFC 6455
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_TEXT: u8 = 0x1;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+// Clients only send control frames we care about, which are at most 125 bytes
+const MAX_CLIENT_PAYLOAD: u64 = 64 * 1024;
+
+#[derive(Clone, Default)]
+struct DeckState {
+    bpm: f32,
+    original_bpm: f32,
+    beat: f32,
+    time: f32,
+    length: f32,
+    phrase: String,
+    next_phrase: String,
+    next_phrase_in: i32,
+    play_state: Option<PlayState>,
+    track: TrackInfo,
+}
+
+impl DeckState {
+    fn to_json(&self) -> Value {
+        json!({
+            "bpm": self.bpm,
+            "original_bpm": self.original_bpm,
+            "beat": self.beat,
+            "time": self.time,
+            "length": self.length,
+            "phrase": self.phrase,
+            "next_phrase": self.next_phrase,
+            "next_phrase_in": self.next_phrase_in,
+            "play_state": self.play_state.map(|s| s.name()),
+            "track": track_json(&self.track),
+        })
+    }
+}
+
+/// Latest value of everything, for clients that just connected
+#[derive(Default)]
+struct State {
+    master_deck: usize,
+    master: DeckState,
+    decks: Vec<DeckState>,
+}
+
+impl State {
+    fn update(&mut self, target: Target, event: &Event) {
+        if let Event::MasterDeck(deck) = event {
+            self.master_deck = *deck;
+            return;
+        }
+        let deck = match target {
+            Target::Master => &mut self.master,
+            Target::Deck(i) => {
+                if self.decks.len() <= i {
+                    self.decks.resize_with(i + 1, Default::default);
+                }
+                &mut self.decks[i]
+            }
+        };
+        match event {
+            Event::Bpm(bpm) => deck.bpm = *bpm,
+            Event::OriginalBpm(bpm) => deck.original_bpm = *bpm,
+            Event::Beat(beat) => deck.beat = *beat,
+            Event::Time(time) => deck.time = *time,
+            Event::TrackLength(length) => deck.length = *length,
+            Event::Phrase(phrase) => deck.phrase = phrase.clone(),
+            Event::NextPhrase(phrase) => deck.next_phrase = phrase.clone(),
+            Event::NextPhraseIn(beats) => deck.next_phrase_in = *beats,
+            Event::PlayState(state) => deck.play_state = Some(*state),
+            Event::Track(track) => deck.track = track.clone(),
+            _ => (),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        json!({
+            "type": "state",
+            "master_deck": self.master_deck,
+            "master": self.master.to_json(),
+            "decks": self.decks.iter().map(DeckState::to_json).collect::<Vec<Value>>(),
+        })
+        .to_string()
+    }
+}
+
+/// Writing half of a WebSocket connection, shared between broadcasts and replies to the client
+type Client = Arc<Mutex<TcpStream>>;
+
+/// Serves the current state as JSON over HTTP and pushes every event to WebSocket clients
+pub struct WebSocket {
+    state: Arc<Mutex<State>>,
+    clients: Arc<Mutex<Vec<Client>>>,
+    send_period: i32,
+    send_period_counter: i32,
+    logger: ScopedLogger,
+}
+
+impl WebSocket {
+    pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
+        let address = conf.get_or_default("address", "127.0.0.1:4480".to_string());
+        let listener = match TcpListener::bind(&address) {
+            Ok(l) => l,
+            Err(e) => {
+                logger.err(&format!("Failed to start server on {address}: {e}"));
+                return Err(());
+            }
+        };
+        logger.info(&format!("Serving state on http://{address}/state and ws://{address}/"));
+
+        Ok(Box::new(WebSocket::serve(listener, conf.get_or_default("send_every_nth", 2i32), logger)))
+    }
+
+    fn serve(listener: TcpListener, send_period: i32, logger: ScopedLogger) -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let clients = Arc::new(Mutex::new(vec![]));
+        let (server_state, server_clients) = (state.clone(), clients.clone());
+        http::serve(listener, move |stream, request| handle_connection(stream, request, &server_state, &server_clients));
+
+        WebSocket {
+            state,
+            clients,
+            send_period: send_period.max(1),
+            send_period_counter: 0,
+            logger,
+        }
+    }
+
+    fn broadcast(&self, message: &str) {
+        let frame = frame(OP_TEXT, message.as_bytes());
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        let before = clients.len();
+        clients.retain(|client| client.lock().unwrap_or_else(|e| e.into_inner()).write_all(&frame).is_ok());
+        if clients.len() < before {
+            self.logger.debug(&format!("{} client(s) disconnected", before - clients.len()));
+        }
+    }
+}
+
+impl OutputModule for WebSocket {
+    fn pre_update(&mut self) {
+        self.send_period_counter = (self.send_period_counter + 1) % self.send_period;
+    }
+
+    fn on_event(&mut self, target: Target, event: &Event) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).update(target, event);
+
+        // Values changing every update are thinned out like the OSC time-critical messages
+        let frequent = matches!(
+            event,
+            Event::Beat(_)
+                | Event::TrackBeat(_)
+                | Event::PhraseBeat(_)
+                | Event::Time(_)
+                | Event::TimeRemaining(_)
+                | Event::Progress(_)
+                | Event::Energy(_)
+                | Event::Bands(_)
+        );
+        if frequent && self.send_period_counter != 0 {
+            return;
+        }
+        let target = match target {
+            Target::Master => json!("master"),
+            Target::Deck(i) => json!(i),
+        };
+        let (name, value) = event_json(event);
+        self.broadcast(&json!({"type": "event", "target": target, "event": name, "value": value}).to_string());
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, request: Request, state: &Mutex<State>, clients: &Mutex<Vec<Client>>) -> io::Result<()> {
+    if request.method != "GET" {
+        return http::respond(&stream, "405 Method Not Allowed", "text/plain", b"");
+    }
+
+    if let Some(key) = request.header("sec-websocket-key") {
+        write!(
+            stream,
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        )?;
+        // A stalled client must not block the keeper, an idle one is fine
+        stream.set_write_timeout(Some(Duration::from_millis(100)))?;
+        stream.set_read_timeout(None)?;
+        let client = Arc::new(Mutex::new(stream.try_clone()?));
+        {
+            // Registered while holding the list, so no event goes out before the state
+            let mut clients = clients.lock().unwrap_or_else(|e| e.into_inner());
+            let snapshot = state.lock().unwrap_or_else(|e| e.into_inner()).to_json();
+            client.lock().unwrap_or_else(|e| e.into_inner()).write_all(&frame(OP_TEXT, snapshot.as_bytes()))?;
+            clients.push(client.clone());
+        }
+        return read_client(&mut stream, &client);
+    }
+
+    if request.path == "/state" {
+        let body = state.lock().unwrap_or_else(|e| e.into_inner()).to_json();
+        http::respond(&stream, "200 OK", "application/json", body.as_bytes())
+    } else {
+        http::respond(&stream, "404 Not Found", "text/plain", b"")
+    }
+}
+
+/// Answer pings and closes until the client goes away. Shutting the connection down makes the next
+/// broadcast fail, which drops the client from the list.
+fn read_client(reader: &mut TcpStream, client: &Client) -> io::Result<()> {
+    let result = loop {
+        let (opcode, payload) = match read_frame(reader) {
+            Ok(frame) => frame,
+            Err(e) => break Err(e),
+        };
+        let mut writer = client.lock().unwrap_or_else(|e| e.into_inner());
+        match opcode {
+            OP_PING => {
+                if let Err(e) = writer.write_all(&frame(OP_PONG, &payload)) {
+                    break Err(e);
+                }
+            }
+            OP_CLOSE => {
+                // Echo the status code, the client closes the connection after receiving it
+                break writer.write_all(&frame(OP_CLOSE, &payload[..payload.len().min(2)]));
+            }
+            // Nothing is expected from clients
+            _ => (),
+        }
+    };
+    reader.shutdown(Shutdown::Both).ok();
+    result
+}
+
+/// Read one frame, unmasking it if needed. Fragmented messages are returned frame by frame.
+fn read_frame(reader: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header)?;
+    let opcode = header[0] & 0x0F;
+    let masked = header[1] & 0x80 != 0;
+    let len = match header[1] & 0x7F {
+        126 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if len > MAX_CLIENT_PAYLOAD {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut mask = [0u8; 4];
+    if masked {
+        reader.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((opcode, payload))
+}
+
+/// `Sec-WebSocket-Accept` value for the key sent by the client
+fn accept_key(key: &str) -> String {
+    BASE64.encode(Sha1::digest(format!("{key}{WEBSOCKET_GUID}").as_bytes()))
+}
+
+fn event_json(event: &Event) -> (&'static str, Value) {
+    match event {
+        Event::Bpm(bpm) => ("bpm", json!(bpm)),
+        Event::OriginalBpm(bpm) => ("original_bpm", json!(bpm)),
+        Event::Beat(beat) => ("beat", json!(beat)),
+        Event::TrackBeat(beat) => ("track_beat", json!(beat)),
+        Event::PhraseBeat(beat) => ("phrase_beat", json!(beat)),
+        Event::BeatCount(count) => (
+            "beat_count",
+            json!({"beat": count.beat, "bar": count.bar, "beat_in_bar": count.beat_in_bar, "total_beats": count.total_beats}),
+        ),
+        Event::Time(time) => ("time", json!(time)),
+        Event::TrackLength(length) => ("track_length", json!(length)),
+        Event::TimeRemaining(remaining) => ("time_remaining", json!(remaining)),
+        Event::Progress(progress) => ("progress", json!(progress)),
+        Event::Track(track) => ("track", track_json(track)),
+        Event::Phrase(phrase) => ("phrase", json!(phrase)),
+        Event::NextPhrase(phrase) => ("next_phrase", json!(phrase)),
+        Event::NextPhraseIn(beats) => ("next_phrase_in", json!(beats)),
+        Event::NextCue(cue) => ("next_cue", cue.as_ref().map(cue_json).unwrap_or(Value::Null)),
+        Event::NextCueIn(beats) => ("next_cue_in", json!(beats)),
+        Event::CuePassed(cue) => ("cue_passed", cue_json(cue)),
+        Event::Energy(energy) => ("energy", json!(energy)),
+        Event::Bands(bands) => ("bands", json!({"low": bands.low, "mid": bands.mid, "high": bands.high})),
+        Event::PlayState(state) => ("play_state", json!(state.name())),
+        Event::MasterDeck(deck) => ("master_deck", json!(deck)),
+    }
+}
+
+fn track_json(track: &TrackInfo) -> Value {
+    json!({
+        "title": track.title,
+        "artist": track.artist,
+        "album": track.album,
+        "key": track.key,
+        "genre": track.genre,
+        "label": track.label,
+        "rating": track.rating,
+        "comment": track.comment,
+        "bpm": track.bpm,
+        "duration": track.duration,
+        "artwork": track.artwork,
+        "file_path": track.file_path,
+    })
+}
+
+fn cue_json(cue: &Cue) -> Value {
+    json!({
+        "name": cue.name(),
+        "hot_cue": cue.hot_cue,
+        "time": cue.time,
+        "color": cue.color.map(|(r, g, b)| [r, g, b]),
+        "comment": cue.comment,
+    })
+}
+
+/// Unmasked single frame message, as sent by servers
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode]; // FIN
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::rc::Rc;
+
+    use crate::log::Logger;
+
+    /// Masked frame, as sent by clients
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_6455_example() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn frame_lengths() {
+        assert_eq!(frame(OP_TEXT, b"hi"), [0x81, 2, b'h', b'i']);
+
+        let medium = "a".repeat(126);
+        let frame_medium = frame(OP_TEXT, medium.as_bytes());
+        assert_eq!(frame_medium[..4], [0x81, 126, 0, 126]);
+        assert_eq!(frame_medium.len(), 4 + 126);
+
+        let long = "a".repeat(0x10000);
+        let frame_long = frame(OP_TEXT, long.as_bytes());
+        assert_eq!(frame_long[..10], [0x81, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(frame_long.len(), 10 + 0x10000);
+    }
+
+    #[test]
+    fn reads_masked_and_unmasked_frames() {
+        let (opcode, payload) = read_frame(&mut client_frame(OP_PING, b"abcde").as_slice()).unwrap();
+        assert_eq!((opcode, payload.as_slice()), (OP_PING, &b"abcde"[..]));
+
+        let long = "a".repeat(300);
+        let (opcode, payload) = read_frame(&mut frame(OP_TEXT, long.as_bytes()).as_slice()).unwrap();
+        assert_eq!((opcode, payload), (OP_TEXT, long.into_bytes()));
+
+        assert!(read_frame(&mut [0x81, 127, 0, 0, 0, 1, 0, 0, 0, 0].as_slice()).is_err());
+    }
+
+    #[test]
+    fn state_json_tracks_events() {
+        let mut state = State::default();
+        state.update(Target::Master, &Event::Bpm(128.));
+        state.update(Target::Deck(1), &Event::PlayState(PlayState::Playing));
+        state.update(Target::Master, &Event::MasterDeck(1));
+        let json = state.to_json();
+        assert!(json.starts_with("{\"type\":\"state\",\"master_deck\":1,\"master\":{\"bpm\":128.0,"));
+        assert!(json.contains("\"play_state\":\"playing\""));
+        // Deck 0 is filled in so indices line up
+        assert_eq!(state.decks.len(), 2);
+        // JSON has no NaN
+        state.update(Target::Master, &Event::Beat(f32::NAN));
+        assert!(state.to_json().contains("\"beat\":null"));
+    }
+
+    #[test]
+    fn round_trip_over_localhost() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut module = WebSocket::serve(listener, 1, ScopedLogger::new(&Rc::new(Logger::new(false)), "WebSocket"));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\nHost: {address}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        )
+        .unwrap();
+
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut response = vec![];
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            response.push(line.trim().to_string());
+        }
+        assert_eq!(response[0], "HTTP/1.1 101 Switching Protocols");
+        assert!(response.contains(&"Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string()));
+
+        // The state comes first, the client is registered once it is sent
+        let (opcode, payload) = read_frame(&mut reader).unwrap();
+        assert_eq!(opcode, OP_TEXT);
+        assert!(String::from_utf8(payload).unwrap().starts_with("{\"type\":\"state\""));
+
+        module.on_event(Target::Master, &Event::Bpm(128.));
+        let (opcode, payload) = read_frame(&mut reader).unwrap();
+        assert_eq!(opcode, OP_TEXT);
+        assert_eq!(
+            String::from_utf8(payload).unwrap(),
+            "{\"type\":\"event\",\"target\":\"master\",\"event\":\"bpm\",\"value\":128.0}"
+        );
+
+        stream.write_all(&client_frame(OP_PING, b"ping")).unwrap();
+        assert_eq!(read_frame(&mut reader).unwrap(), (OP_PONG, b"ping".to_vec()));
+
+        stream.write_all(&client_frame(OP_CLOSE, &1000u16.to_be_bytes())).unwrap();
+        assert_eq!(read_frame(&mut reader).unwrap(), (OP_CLOSE, 1000u16.to_be_bytes().to_vec()));
+        // The server hangs up and drops the client on the next broadcast
+        assert_eq!(reader.read(&mut [0u8; 1]).unwrap(), 0);
+        module.on_event(Target::Master, &Event::Bpm(129.));
+        assert!(module.clients.lock().unwrap().is_empty());
+    }
+}
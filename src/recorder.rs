@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::log::ScopedLogger;
+use crate::memory::replay_memory::escape_value;
+
+/// Records raw deck state to a session file in the timeline format read by the replay backend
+/// (see `memory::replay_memory`). Values are only written when they change, together with the
+/// last time the previous value was seen.
+pub struct Recorder {
+    writer: BufWriter<File>,
+    start: Instant,
+    // Last value per key, when it was last seen and whether it was written at that time
+    last_values: HashMap<String, HeldValue>,
+    logger: ScopedLogger,
+    failed: bool,
+}
+
+struct HeldValue {
+    value: String,
+    seen: f64,
+    written: bool,
+}
+
+impl Recorder {
+    /// `{time}` in the filename is replaced with the current Unix time, so restarts don't overwrite old sessions
+    pub fn new(filename: &str, logger: ScopedLogger) -> Option<Self> {
+        let unix_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let filename = filename.replace("{time}", &unix_time.to_string());
+
+        let mut file = match File::create(&filename) {
+            Ok(f) => BufWriter::new(f),
+            Err(e) => {
+                logger.err(&format!("Failed to create recording file {filename}: {e}"));
+                return None;
+            }
+        };
+        if let Err(e) = writeln!(file, "# rkbx_link session recorded at {unix_time}") {
+            logger.err(&format!("Failed to write to recording file: {e}"));
+            return None;
+        }
+        logger.info(&format!("Recording session to {filename}"));
+
+        Some(Recorder {
+            writer: file,
+            start: Instant::now(),
+            last_values: HashMap::new(),
+            logger,
+            failed: false,
+        })
+    }
+
+    pub fn masterdeck(&mut self, deck: usize) {
+        self.write("master", deck.to_string());
+    }
+
+    pub fn timing(&mut self, deck: usize, bpm: f32, sample_position: i64) {
+        self.write(&format!("bpm {deck}"), bpm.to_string());
+        self.write(&format!("position {deck}"), sample_position.to_string());
+    }
+
+    /// `raw` is the track info text as read from memory
+    pub fn track_info(&mut self, deck: usize, raw: &[u8]) {
+        self.write(&format!("track {deck}"), escape_value(&String::from_utf8_lossy(raw)));
+    }
+
+    pub fn anlz_path(&mut self, deck: usize, path: &str) {
+        self.write(&format!("anlz {deck}"), path.to_string());
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            self.fail(e);
+        }
+    }
+
+    fn write(&mut self, key: &str, value: String) {
+        if self.failed {
+            return;
+        }
+        let time = self.start.elapsed().as_secs_f64() * 1000.;
+        if let Some(held) = self.last_values.get_mut(key) {
+            if held.value == value {
+                held.seen = time;
+                held.written = false;
+                return;
+            }
+            // Replay interpolates positions between keyframes, so mark where a held value ended,
+            // otherwise a paused deck would appear to play slowly through the pause
+            if !held.written {
+                if let Err(e) = writeln!(self.writer, "{:.1} {key} {}", held.seen, held.value) {
+                    self.fail(e);
+                    return;
+                }
+            }
+        }
+        if let Err(e) = writeln!(self.writer, "{time:.1} {key} {value}") {
+            self.fail(e);
+            return;
+        }
+        self.last_values.insert(key.to_string(), HeldValue { value, seen: time, written: true });
+    }
+
+    fn fail(&mut self, e: std::io::Error) {
+        self.logger.err(&format!("Failed to write to recording file, stopping recording: {e}"));
+        self.failed = true;
+    }
+}
@@ -0,0 +1,78 @@
+// Drives the keeper from a synthetic replay timeline and checks the events it emits
+
+use std::fs;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rkbx_link::config::Config;
+use rkbx_link::events::PlayState;
+use rkbx_link::log::{Logger, ScopedLogger};
+use rkbx_link::{BeatKeeper, Event, RekordboxOffsets, Target};
+
+// One deck playing at normal speed for 10 seconds
+const TIMELINE: &str = "\
+0 master 0
+0 bpm 0 128
+0 track 0 Title: Replayed Track\\nArtist: Someone\\nAlbum: Somewhere
+0 position 0 0
+10000 position 0 441000
+";
+
+fn offsets(logger: &Rc<Logger>) -> RekordboxOffsets {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/data/offsets");
+    let offsets = RekordboxOffsets::from_file(path, ScopedLogger::new(logger, "Offsets")).unwrap();
+    offsets.into_values().next().expect("No versions in the offsets file")
+}
+
+#[test]
+fn replay_emits_events() {
+    let path = std::env::temp_dir().join(format!("rkbx_link_replay_{}.txt", std::process::id()));
+    fs::write(&path, TIMELINE).unwrap();
+
+    let logger = Rc::new(Logger::new(false));
+    let config = Config::parse(
+        &format!(
+            "keeper.source replay\nkeeper.replay_file {}\nkeeper.replay_loop false\nkeeper.decks 1\n",
+            path.display()
+        ),
+        ScopedLogger::new(&logger, "Config"),
+    );
+    let mut keeper = BeatKeeper::new(offsets(&logger), vec![], config, ScopedLogger::new(&logger, "BeatKeeper")).unwrap();
+    let events = keeper.subscribe();
+
+    // The first poll connects, the following ones update
+    let end = Instant::now() + Duration::from_millis(500);
+    while Instant::now() < end {
+        let wait = keeper.poll();
+        thread::sleep(wait.min(Duration::from_millis(20)));
+    }
+    fs::remove_file(&path).ok();
+
+    let events: Vec<(Target, Event)> = events.try_iter().collect();
+    assert!(events.contains(&(Target::Master, Event::MasterDeck(0))));
+    assert!(events.contains(&(Target::Master, Event::Bpm(128.))));
+    assert!(events.contains(&(Target::Deck(0), Event::Bpm(128.))));
+    assert!(events.contains(&(Target::Master, Event::PlayState(PlayState::Playing))));
+
+    let track = events.iter().find_map(|(target, event)| match (target, event) {
+        (Target::Master, Event::Track(track)) => Some(track),
+        _ => None,
+    });
+    let track = track.expect("No track event for the master deck");
+    assert_eq!(track.title, "Replayed Track");
+    assert_eq!(track.artist, "Someone");
+
+    // Follows the timeline, so moves forward at about real time
+    let times: Vec<f32> = events
+        .iter()
+        .filter_map(|(target, event)| match (target, event) {
+            (Target::Master, Event::Time(time)) => Some(*time),
+            _ => None,
+        })
+        .collect();
+    assert!(times.len() > 2);
+    assert!(times.windows(2).all(|w| w[1] >= w[0]));
+    let last = *times.last().unwrap();
+    assert!(last > 0.2 && last < 1.0, "Replay time {last}");
+}